@@ -0,0 +1,181 @@
+use crate::diagnostic::Span;
+use crate::graph::invocations;
+use crate::preprocessor::{KeptGroup, Preprocessed};
+use crate::trace::{Event, Node};
+
+/// Normalizes preprocessed code so that outputs differing only in blank lines and in
+/// spaces at the end of lines compare equal.
+pub fn standardize(mut s: String) -> String {
+    loop {
+        let mut new = s.replace("\n\n", "\n");
+        new = new.replace(" \n", "\n");
+        if new.len() == s.len() {
+            break;
+        }
+        s = new;
+    }
+    s.trim().to_string()
+}
+
+/// Consecutive lines of the output that differ from a reference preprocessor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Where the first differing line of the output starts in the source, or the line after
+    /// them if the output misses lines.
+    pub span: Option<Span>,
+    /// The lines of the output, after `standardize`.
+    pub actual: Vec<String>,
+    /// The lines of the reference, after `standardize`.
+    pub expected: Vec<String>,
+    /// For each source line of `actual`, the steps of the conditional directives keeping it
+    /// that are not given yet, followed by the top-level invocations on it.
+    pub trace: Vec<Node>,
+}
+
+/// Compares the output with the output of a reference preprocessor, such as `cc -E -P`,
+/// after `standardize`.
+pub fn compare(result: &Preprocessed, reference: &str) -> Vec<Mismatch> {
+    // `standardize` drops the lines left empty once the trailing spaces are removed.
+    let spans: Vec<&Span> = result
+        .line_spans
+        .iter()
+        .zip(result.output.split('\n'))
+        .filter(|(_, line)| !line.trim_end_matches(' ').is_empty())
+        .map(|(span, _)| span)
+        .collect();
+    let actual = standardize(result.output.clone());
+    let actual: Vec<&str> = actual.lines().collect();
+    let expected = standardize(reference.to_string());
+    let expected: Vec<&str> = expected.lines().collect();
+
+    let mut mismatches = Vec::new();
+    let mut hunk: Option<Mismatch> = None;
+    for edit in diff(&actual, &expected) {
+        let (i, j) = match edit {
+            Edit::Equal => {
+                mismatches.extend(hunk.take());
+                continue;
+            }
+            Edit::Delete(i) => (Some(i), None),
+            Edit::Insert(i, j) => (None, Some((i, j))),
+        };
+        let mismatch = hunk.get_or_insert_with(|| Mismatch {
+            span: spans
+                .get(i.unwrap_or_else(|| j.unwrap().0))
+                .map(|&span| span.clone()),
+            actual: Vec::new(),
+            expected: Vec::new(),
+            trace: Vec::new(),
+        });
+        if let Some(i) = i {
+            mismatch.actual.push(actual[i].to_string());
+            if let Some(span) = spans.get(i) {
+                let mut groups: Vec<&KeptGroup> = result
+                    .kept_groups
+                    .iter()
+                    .filter(|group| keeps(group, span))
+                    .collect();
+                groups.sort_by_key(|group| group.start.line);
+                for group in groups {
+                    for node in directives(&result.trace, group) {
+                        if !mismatch.trace.contains(node) {
+                            mismatch.trace.push(node.clone());
+                        }
+                    }
+                }
+                let on_line = invocations(&result.trace).into_iter().filter(|node| {
+                    let invocation = &node.event.site().unwrap().invocation;
+                    invocation.file == span.file && invocation.line == span.line
+                });
+                mismatch.trace.extend(on_line.cloned());
+            }
+        }
+        if let Some((_, j)) = j {
+            mismatch.expected.push(expected[j].to_string());
+        }
+    }
+    mismatches.extend(hunk);
+    mismatches
+}
+
+/// Whether a line is in a kept group.
+fn keeps(group: &KeptGroup, span: &Span) -> bool {
+    group.start.file == span.file
+        && group.start.line < span.line
+        && group.end.as_ref().is_none_or(|end| span.line < end.line)
+}
+
+/// Returns the steps of the directives of a conditional up to the one keeping `group`.
+/// The groups before it are skipped, so no step of a nested conditional is among them.
+fn directives<'a>(trace: &'a [Node], group: &KeptGroup) -> Vec<&'a Node> {
+    let mut result = Vec::new();
+    for node in trace {
+        let span = match &node.event {
+            Event::Condition { span, .. }
+            | Event::Ifdef { span, .. }
+            | Event::ElseKept { span, .. } => span,
+            _ => {
+                result.extend(directives(&node.children, group));
+                continue;
+            }
+        };
+        if span.file == group.start.file
+            && (group.conditional.line..=group.start.line).contains(&span.line)
+        {
+            result.push(node);
+        }
+    }
+    result
+}
+
+/// A step turning the lines `a` into the lines `b`, which keeps, removes or adds a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    /// The next lines of `a` and `b` are the same.
+    Equal,
+    /// `a[i]` is removed.
+    Delete(usize),
+    /// `b[j]` is inserted before `a[i]`.
+    Insert(usize, usize),
+}
+
+/// Finds the fewest lines to delete from `a` and insert into it to get `b`, deleting before
+/// inserting.
+fn diff(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (n, m) = (a.len() - prefix - suffix, b.len() - prefix - suffix);
+    // `lcs[i][j]` is the length of the longest common subsequence of the ends of the
+    // middle parts starting at `i` and `j`.
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[prefix + i] == b[prefix + j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut edits = vec![Edit::Equal; prefix];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[prefix + i] == b[prefix + j] {
+            edits.push(Edit::Equal);
+            (i, j) = (i + 1, j + 1);
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            edits.push(Edit::Delete(prefix + i));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(prefix + i, prefix + j));
+            j += 1;
+        }
+    }
+    edits.extend(std::iter::repeat_n(Edit::Equal, suffix));
+    edits
+}
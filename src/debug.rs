@@ -0,0 +1,28 @@
+use crate::rewrite::Rewrite;
+use crate::trace::{Binding, Site};
+
+/// A macro invocation about to be replaced, after its arguments are pre-expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    pub site: Site,
+    /// How deep the invocation is nested in the pre-expansions and rescans of other ones.
+    /// An invocation inside another one is deeper, and the next one after it is not.
+    pub depth: usize,
+    /// The arguments of a function-like macro, empty for an object-like macro.
+    pub bindings: Vec<Binding>,
+    /// The macros the macro name is hidden from.
+    pub hide_set: Vec<String>,
+    /// The tokens replacing the invocation.
+    pub replacement: String,
+    /// The macros the replacement is hidden from while it is rescanned.
+    pub replacement_hide_set: Vec<String>,
+    /// The invocation in the line being scanned.
+    pub rewrite: Rewrite,
+}
+
+/// Pauses the expansion before each macro replacement, such as to step through it
+/// interactively.
+pub trait Debugger {
+    /// Called before `replacement` is made. The expansion resumes when it returns.
+    fn before_replacement(&mut self, replacement: &Replacement);
+}
@@ -0,0 +1,157 @@
+use std::io::{BufRead, Lines, StdinLock, Write};
+
+use c_macro_explainer::{Debugger, Replacement};
+
+const HELP: &str = "\
+Commands:
+  step, s               pause before the next replacement
+  next, n               finish the current invocation and pause after it
+  finish, f             finish the invocation whose argument or rescan this one is in
+  continue, c           run until a breakpoint
+  break, b PATTERN      pause before replacing the macros matching PATTERN, where `*`
+                        matches any characters and `?` one
+  delete, d [PATTERN]   remove a breakpoint, or every one
+  info, i               list the breakpoints
+  print, p [PARAM]      print the argument bindings, or the argument of PARAM
+  hide, h               print the hide sets of the macro name and the replacement
+  where, w              print the line being rewritten
+  help                  show this message
+  quit, q               stop preprocessing";
+
+/// When to pause next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Step,
+    /// Pause at a replacement at most this deep.
+    Next(usize),
+    /// Pause at a replacement less deep than this.
+    Finish(usize),
+    Continue,
+}
+
+/// Steps through the expansion with commands read from the standard input.
+pub struct Console {
+    mode: Mode,
+    breakpoints: Vec<String>,
+    lines: Lines<StdinLock<'static>>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        println!("Paused before the first replacement. Type help for the commands.");
+        Self {
+            mode: Mode::Step,
+            breakpoints: Vec::new(),
+            lines: std::io::stdin().lock().lines(),
+        }
+    }
+
+    fn should_pause(&self, replacement: &Replacement) -> bool {
+        let is_breakpoint = self
+            .breakpoints
+            .iter()
+            .any(|pattern| matches(pattern.as_bytes(), replacement.site.name.as_bytes()));
+        is_breakpoint
+            || match self.mode {
+                Mode::Step => true,
+                Mode::Next(depth) => replacement.depth <= depth,
+                Mode::Finish(depth) => replacement.depth < depth,
+                Mode::Continue => false,
+            }
+    }
+}
+
+impl Debugger for Console {
+    fn before_replacement(&mut self, replacement: &Replacement) {
+        if !self.should_pause(replacement) {
+            return;
+        }
+        let site = &replacement.site;
+        println!(
+            "{}: {} is about to be replaced by `{}` (depth {})",
+            site.invocation, site.name, replacement.replacement, replacement.depth
+        );
+        loop {
+            print!("(debug) ");
+            std::io::stdout().flush().unwrap();
+            // Without more commands, the rest of the expansion runs to the end.
+            let Some(Ok(line)) = self.lines.next() else {
+                self.mode = Mode::Continue;
+                self.breakpoints.clear();
+                return;
+            };
+            let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+                Some((command, argument)) => (command, argument.trim()),
+                None => (line.trim(), ""),
+            };
+            match command {
+                "step" | "s" => self.mode = Mode::Step,
+                "next" | "n" => self.mode = Mode::Next(replacement.depth),
+                "finish" | "f" => self.mode = Mode::Finish(replacement.depth),
+                "continue" | "c" => self.mode = Mode::Continue,
+                "break" | "b" if !argument.is_empty() => {
+                    self.breakpoints.push(argument.to_string());
+                    println!("Breakpoint on {}.", argument);
+                    continue;
+                }
+                "delete" | "d" => {
+                    self.breakpoints
+                        .retain(|pattern| !argument.is_empty() && pattern != argument);
+                    continue;
+                }
+                "info" | "i" => {
+                    for pattern in &self.breakpoints {
+                        println!("{}", pattern);
+                    }
+                    continue;
+                }
+                "print" | "p" => {
+                    let bindings = replacement
+                        .bindings
+                        .iter()
+                        .filter(|binding| argument.is_empty() || binding.param == argument);
+                    for binding in bindings {
+                        println!("{}", binding);
+                    }
+                    continue;
+                }
+                "hide" | "h" => {
+                    println!("{}: {{{}}}", site.name, replacement.hide_set.join(", "));
+                    println!(
+                        "replacement: {{{}}}",
+                        replacement.replacement_hide_set.join(", ")
+                    );
+                    continue;
+                }
+                "where" | "w" => {
+                    let rewrite = std::slice::from_ref(&replacement.rewrite);
+                    print!("{}", c_macro_explainer::rewrite_view(rewrite));
+                    continue;
+                }
+                "quit" | "q" => std::process::exit(0),
+                "help" => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                _ => {
+                    eprintln!("error: unknown command `{}`; type help", line.trim());
+                    continue;
+                }
+            }
+            return;
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any characters and `?` one.
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => {
+            matches(rest, name) || !name.is_empty() && matches(pattern, &name[1..])
+        }
+        (Some((b'?', rest)), Some((_, name))) => matches(rest, name),
+        (Some((c, rest)), Some((n, name))) => c == n && matches(rest, name),
+        (Some(_), None) => false,
+    }
+}
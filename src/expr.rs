@@ -0,0 +1,673 @@
+//! Evaluation of the controlling expression of `#if` and `#elif`.
+//!
+//! The expression is expected to be fully macro-expanded already. Identifiers that are
+//! still left in it are replaced by `0`, as required by the C standard. All arithmetic is
+//! done in `intmax_t` or `uintmax_t`, both of which are 64 bits wide here.
+
+use std::fmt;
+
+use crate::lexer::{self, PpToken};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+    Not,
+    BitNot,
+    Question,
+    Colon,
+    LParen,
+    RParen,
+}
+
+const OPERATORS: [(&str, Op); 24] = [
+    ("<<", Op::Shl),
+    (">>", Op::Shr),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("&&", Op::And),
+    ("||", Op::Or),
+    ("+", Op::Add),
+    ("-", Op::Sub),
+    ("*", Op::Mul),
+    ("/", Op::Div),
+    ("%", Op::Rem),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+    ("&", Op::BitAnd),
+    ("^", Op::BitXor),
+    ("|", Op::BitOr),
+    ("!", Op::Not),
+    ("~", Op::BitNot),
+    ("?", Op::Question),
+    (":", Op::Colon),
+    ("(", Op::LParen),
+    (")", Op::RParen),
+];
+
+impl Op {
+    fn binary_precedence(self) -> Option<u8> {
+        Some(match self {
+            Op::Mul | Op::Div | Op::Rem => 10,
+            Op::Add | Op::Sub => 9,
+            Op::Shl | Op::Shr => 8,
+            Op::Lt | Op::Gt | Op::Le | Op::Ge => 7,
+            Op::Eq | Op::Ne => 6,
+            Op::BitAnd => 5,
+            Op::BitXor => 4,
+            Op::BitOr => 3,
+            Op::And => 2,
+            Op::Or => 1,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (spelling, _) = OPERATORS.iter().find(|(_, op)| op == self).unwrap();
+        f.write_str(spelling)
+    }
+}
+
+/// The value of an integer constant expression, which is either an `intmax_t` or an
+/// `uintmax_t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+impl Value {
+    pub fn is_true(self) -> bool {
+        self.bits() != 0
+    }
+
+    fn bits(self) -> u64 {
+        match self {
+            Value::Signed(value) => value as u64,
+            Value::Unsigned(value) => value,
+        }
+    }
+
+    fn is_unsigned(self) -> bool {
+        matches!(self, Value::Unsigned(_))
+    }
+
+    fn with_signedness(self, is_unsigned: bool) -> Value {
+        if is_unsigned {
+            Value::Unsigned(self.bits())
+        } else {
+            Value::Signed(self.bits() as i64)
+        }
+    }
+
+    fn from_bool(value: bool) -> Value {
+        Value::Signed(value as i64)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Signed(value) => write!(f, "{}", value),
+            Value::Unsigned(value) => write!(f, "{}u", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value, String),
+    /// An identifier, and whether it names a function-like macro.
+    Identifier(String, bool),
+    Op(Op),
+}
+
+/// Converts the pp-tokens of the expression into the tokens of the expression grammar.
+fn tokenize(
+    input: &[PpToken],
+    is_function_like: &dyn Fn(&str) -> bool,
+) -> Result<Vec<Token>, String> {
+    input
+        .iter()
+        .filter(|token| !token.is_padding() && token.kind != lexer::Token::Placemarker)
+        .map(|token| {
+            let spelling = token.text.to_string();
+            match token.kind {
+                lexer::Token::Number => Ok(Token::Number(parse_integer(&spelling)?, spelling)),
+                lexer::Token::CharLiteral => {
+                    Ok(Token::Number(parse_char_constant(&spelling)?, spelling))
+                }
+                lexer::Token::Identifier => {
+                    let is_function_like = is_function_like(&spelling);
+                    Ok(Token::Identifier(spelling, is_function_like))
+                }
+                lexer::Token::StringLiteral => {
+                    Err("string literal in preprocessor expression".to_string())
+                }
+                _ => match OPERATORS.iter().find(|(op, _)| *op == spelling) {
+                    Some((_, op)) => Ok(Token::Op(*op)),
+                    None => Err(format!("unexpected `{}` in expression", spelling)),
+                },
+            }
+        })
+        .collect()
+}
+
+fn parse_integer(literal: &str) -> Result<Value, String> {
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    let suffix = &literal[digits.len()..];
+    if !["", "l", "ll"].contains(&suffix.to_ascii_lowercase().replace('u', "").as_str())
+        || suffix.matches(['u', 'U']).count() > 1
+    {
+        return Err(format!("invalid suffix `{}` on integer constant", suffix));
+    }
+    let (digits, radix) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if let Some(binary) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (binary, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (&digits[1..], 8)
+    } else {
+        (digits, 10)
+    };
+    if (radix != 16 && digits.contains(['e', 'E'])) || digits.contains(['.', 'p', 'P']) {
+        return Err(format!(
+            "floating constant `{}` in preprocessor expression",
+            literal
+        ));
+    }
+    let value = u64::from_str_radix(digits, radix)
+        .map_err(|_| format!("invalid integer constant `{}`", literal))?;
+    // An unsuffixed constant that does not fit in intmax_t is unsigned.
+    if suffix.contains(['u', 'U']) || value > i64::MAX as u64 {
+        Ok(Value::Unsigned(value))
+    } else {
+        Ok(Value::Signed(value as i64))
+    }
+}
+
+/// A character of a character constant, before it is encoded.
+enum Char {
+    /// A source character or a universal character name, encoded like the prefix says.
+    Scalar(char),
+    /// The value of an octal or hexadecimal escape, which is a single code unit.
+    Unit(u32),
+}
+
+/// Reads the characters between the quotes of a character constant.
+fn parse_chars(literal: &str, content: &str) -> Result<Vec<Char>, String> {
+    let mut chars = Vec::new();
+    let mut input = content.chars().peekable();
+    while let Some(ch) = input.next() {
+        if ch != '\\' {
+            chars.push(Char::Scalar(ch));
+            continue;
+        }
+        let escaped = input
+            .next()
+            .ok_or_else(|| format!("invalid character constant {}", literal))?;
+        chars.push(match escaped {
+            'n' => Char::Unit(0x0a),
+            't' => Char::Unit(0x09),
+            'v' => Char::Unit(0x0b),
+            'b' => Char::Unit(0x08),
+            'r' => Char::Unit(0x0d),
+            'f' => Char::Unit(0x0c),
+            'a' => Char::Unit(0x07),
+            'e' | 'E' => Char::Unit(0x1b),
+            '0'..='7' => {
+                let mut value = escaped.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match input.next_if(|c| c.is_digit(8)) {
+                        Some(digit) => value = value * 8 + digit.to_digit(8).unwrap(),
+                        None => break,
+                    }
+                }
+                Char::Unit(value)
+            }
+            'x' => {
+                let mut value = 0u32;
+                let mut has_digits = false;
+                while let Some(digit) = input.next_if(|c| c.is_ascii_hexdigit()) {
+                    value = value.wrapping_mul(16) + digit.to_digit(16).unwrap();
+                    has_digits = true;
+                }
+                if !has_digits {
+                    return Err(format!(
+                        "\\x used with no following hex digits in {}",
+                        literal
+                    ));
+                }
+                Char::Unit(value)
+            }
+            'u' | 'U' => {
+                let len = if escaped == 'u' { 4 } else { 8 };
+                let digits: String = (0..len)
+                    .map_while(|_| input.next_if(|c| c.is_ascii_hexdigit()))
+                    .collect();
+                let name = format!("\\{}{}", escaped, digits);
+                if digits.len() < len {
+                    return Err(format!("incomplete universal character name {}", name));
+                }
+                let value = u32::from_str_radix(&digits, 16).unwrap();
+                // Only `$`, `@` and `` ` `` of the basic character set may be named.
+                let is_basic = value < 0xa0 && ![0x24, 0x40, 0x60].contains(&value);
+                match char::from_u32(value) {
+                    _ if is_basic => {
+                        return Err(format!("{} is not a valid universal character", name))
+                    }
+                    Some(ch) => Char::Scalar(ch),
+                    None if value > 0x10ffff => {
+                        return Err(format!("{} is outside the UCS codespace", name))
+                    }
+                    None => return Err(format!("{} is not a valid universal character", name)),
+                }
+            }
+            other => Char::Scalar(other),
+        });
+    }
+    Ok(chars)
+}
+
+/// Evaluates a character constant as GCC does on a target with a signed 8-bit `char`, a
+/// 32-bit `int` and `wchar_t`, and UTF-8 as the execution character set.
+fn parse_char_constant(literal: &str) -> Result<Value, String> {
+    let (prefix, content) = literal.split_once('\'').unwrap();
+    let chars = parse_chars(literal, &content[..content.len() - 1])?;
+    // The code units of the constant in the encoding of its type.
+    let units: Vec<u32> = chars
+        .iter()
+        .flat_map(|ch| match (prefix, ch) {
+            ("", Char::Scalar(ch)) => {
+                let mut bytes = [0; 4];
+                let bytes = ch.encode_utf8(&mut bytes).bytes().map(u32::from);
+                bytes.collect::<Vec<_>>()
+            }
+            ("", Char::Unit(unit)) => vec![unit & 0xff],
+            ("u", Char::Scalar(ch)) => {
+                let mut units = [0; 2];
+                ch.encode_utf16(&mut units)
+                    .iter()
+                    .map(|&unit| unit.into())
+                    .collect()
+            }
+            ("u", Char::Unit(unit)) => vec![unit & 0xffff],
+            (_, Char::Scalar(ch)) => vec![*ch as u32],
+            (_, Char::Unit(unit)) => vec![*unit],
+        })
+        .collect();
+    match (prefix, units.as_slice()) {
+        (_, []) => Err(format!("empty character constant {}", literal)),
+        // A plain char is signed, so a single byte is sign-extended from 8 bits.
+        ("", [byte]) => Ok(Value::Signed(*byte as u8 as i8 as i64)),
+        // A multi-character constant, which a character encoded in several bytes is too,
+        // has type int and an implementation-defined value. Like GCC, each byte is shifted
+        // in from the right, and only the last four are kept.
+        ("", bytes) => Ok(Value::Signed(
+            bytes.iter().fold(0u32, |value, byte| value << 8 | byte) as i32 as i64,
+        )),
+        // The other types hold a single code unit, and GCC keeps the last one. `wchar_t` is
+        // signed, while `char16_t` and `char32_t` are unsigned.
+        ("L", units) => Ok(Value::Signed(*units.last().unwrap() as i32 as i64)),
+        ("u" | "U", units) => Ok(Value::Unsigned(*units.last().unwrap() as u64)),
+        _ => Err(format!("invalid character constant {}", literal)),
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(Value, String),
+    Identifier(String, bool),
+    Unary(Op, Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Whether the expression has type `uintmax_t`, which is decided without evaluating it.
+    /// This matters for the operand of `?:` that is not selected, since it still takes part
+    /// in the usual arithmetic conversions.
+    fn is_unsigned(&self) -> bool {
+        match self {
+            Expr::Number(value, _) => value.is_unsigned(),
+            Expr::Identifier(..) => false,
+            Expr::Unary(Op::Not, _) => false,
+            Expr::Unary(_, operand) => operand.is_unsigned(),
+            Expr::Binary(Op::Shl | Op::Shr, lhs, _) => lhs.is_unsigned(),
+            Expr::Binary(
+                Op::Lt | Op::Gt | Op::Le | Op::Ge | Op::Eq | Op::Ne | Op::And | Op::Or,
+                _,
+                _,
+            ) => false,
+            Expr::Binary(_, lhs, rhs) | Expr::Conditional(_, lhs, rhs) => {
+                lhs.is_unsigned() || rhs.is_unsigned()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(_, spelling) => f.write_str(spelling),
+            Expr::Identifier(name, _) => f.write_str(name),
+            Expr::Unary(op, operand) => write!(f, "{}{}", op, operand),
+            Expr::Binary(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+            Expr::Conditional(cond, then, otherwise) => {
+                write!(f, "({} ? {} : {})", cond, then, otherwise)
+            }
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<Op> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: Op) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected `{}` in expression", op))
+        }
+    }
+
+    /// conditional-expression: logical-or-expression ? expression : conditional-expression
+    fn conditional(&mut self) -> Result<Expr, String> {
+        let cond = self.binary(1)?;
+        if self.peek_op() != Some(Op::Question) {
+            return Ok(cond);
+        }
+        self.pos += 1;
+        let then = self.conditional()?;
+        self.expect(Op::Colon)?;
+        let otherwise = self.conditional()?;
+        Ok(Expr::Conditional(
+            Box::new(cond),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    /// Parses binary operators by precedence climbing.
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_op() {
+            let Some(precedence) = op.binary_precedence().filter(|p| *p >= min_precedence) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Number(value, spelling) => Ok(Expr::Number(value, spelling)),
+            Token::Identifier(name, is_function_like) => {
+                Ok(Expr::Identifier(name, is_function_like))
+            }
+            Token::Op(op @ (Op::Add | Op::Sub | Op::Not | Op::BitNot)) => {
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            Token::Op(Op::LParen) => {
+                let expr = self.conditional()?;
+                self.expect(Op::RParen)?;
+                Ok(expr)
+            }
+            Token::Op(op) => Err(format!("unexpected `{}` in expression", op)),
+        }
+    }
+}
+
+/// Evaluates an expression tree, explaining every reduction step in `steps`.
+fn eval(expr: &Expr, steps: &mut Vec<String>) -> Result<Value, String> {
+    match expr {
+        Expr::Number(value, spelling) => {
+            if spelling.ends_with('\'') {
+                steps.push(format!(
+                    "The character constant {} has the value {}",
+                    spelling, value
+                ));
+            }
+            Ok(*value)
+        }
+        Expr::Identifier(name, is_function_like) => {
+            steps.push(match is_function_like {
+                true => format!(
+                    "{} is a function-like macro used without arguments, so it is replaced by 0 in the #if expression",
+                    name
+                ),
+                false => format!(
+                    "{} is not a macro, so it is replaced by 0 in the #if expression",
+                    name
+                ),
+            });
+            Ok(Value::Signed(0))
+        }
+        Expr::Unary(op, operand) => {
+            let value = eval(operand, steps)?;
+            let result = apply_unary(*op, value);
+            if format!("{}{}", op, value) != result.to_string() {
+                steps.push(format!(
+                    "Evaluating `{}{}`. The result is `{}`",
+                    op, value, result
+                ));
+            }
+            Ok(result)
+        }
+        Expr::Binary(op @ (Op::And | Op::Or), lhs, rhs) => {
+            let lhs = eval(lhs, steps)?;
+            if lhs.is_true() == (*op == Op::Or) {
+                let result = Value::from_bool(lhs.is_true());
+                steps.push(format!(
+                    "Evaluating `{} {} {}`. The right operand is not evaluated because the left operand is {}, so the result is `{}`",
+                    lhs, op, rhs, lhs, result
+                ));
+                return Ok(result);
+            }
+            let rhs = eval(rhs, steps)?;
+            let result = Value::from_bool(rhs.is_true());
+            steps.push(format!(
+                "Evaluating `{} {} {}`. The result is `{}`",
+                lhs, op, rhs, result
+            ));
+            Ok(result)
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = eval(lhs, steps)?;
+            let rhs = eval(rhs, steps)?;
+            let result = apply_binary(*op, lhs, rhs)?;
+            let conversion = match (lhs.is_unsigned(), rhs.is_unsigned()) {
+                (false, true) | (true, false) if !matches!(op, Op::Shl | Op::Shr) => {
+                    let signed = if lhs.is_unsigned() { rhs } else { lhs };
+                    format!(
+                        " after converting `{}` to `{}`",
+                        signed,
+                        signed.with_signedness(true)
+                    )
+                }
+                _ => String::new(),
+            };
+            steps.push(format!(
+                "Evaluating `{} {} {}`{}. The result is `{}`",
+                lhs, op, rhs, conversion, result
+            ));
+            Ok(result)
+        }
+        Expr::Conditional(cond, then, otherwise) => {
+            let is_unsigned = then.is_unsigned() || otherwise.is_unsigned();
+            let cond = eval(cond, steps)?;
+            let (selected, skipped, which) = if cond.is_true() {
+                (then, otherwise, "second")
+            } else {
+                (otherwise, then, "third")
+            };
+            let value = eval(selected, steps)?;
+            let result = value.with_signedness(is_unsigned);
+            steps.push(format!(
+                "Evaluating `{} ? ... : ...`. The condition is {}, so the result is the {} operand `{}`{}",
+                cond,
+                if cond.is_true() { "nonzero" } else { "zero" },
+                which,
+                result,
+                if result.is_unsigned() && !value.is_unsigned() {
+                    format!(", converted to unsigned because `{}` is unsigned", skipped)
+                } else {
+                    String::new()
+                }
+            ));
+            Ok(result)
+        }
+    }
+}
+
+fn apply_unary(op: Op, value: Value) -> Value {
+    match (op, value) {
+        (Op::Add, value) => value,
+        (Op::Not, value) => Value::from_bool(!value.is_true()),
+        (Op::Sub, Value::Signed(value)) => Value::Signed(value.wrapping_neg()),
+        (Op::Sub, Value::Unsigned(value)) => Value::Unsigned(value.wrapping_neg()),
+        (Op::BitNot, Value::Signed(value)) => Value::Signed(!value),
+        (Op::BitNot, Value::Unsigned(value)) => Value::Unsigned(!value),
+        _ => unreachable!("{} is not a unary operator", op),
+    }
+}
+
+fn apply_binary(op: Op, lhs: Value, rhs: Value) -> Result<Value, String> {
+    if let Op::Shl | Op::Shr = op {
+        return Ok(shift(op, lhs, rhs));
+    }
+    // The usual arithmetic conversions: if either operand is unsigned, so is the other.
+    let is_unsigned = lhs.is_unsigned() || rhs.is_unsigned();
+    let result = if is_unsigned {
+        let (lhs, rhs) = (lhs.bits(), rhs.bits());
+        match op {
+            Op::Add => Value::Unsigned(lhs.wrapping_add(rhs)),
+            Op::Sub => Value::Unsigned(lhs.wrapping_sub(rhs)),
+            Op::Mul => Value::Unsigned(lhs.wrapping_mul(rhs)),
+            Op::Div | Op::Rem if rhs == 0 => return Err("division by zero in #if".to_string()),
+            Op::Div => Value::Unsigned(lhs / rhs),
+            Op::Rem => Value::Unsigned(lhs % rhs),
+            Op::Lt => Value::from_bool(lhs < rhs),
+            Op::Gt => Value::from_bool(lhs > rhs),
+            Op::Le => Value::from_bool(lhs <= rhs),
+            Op::Ge => Value::from_bool(lhs >= rhs),
+            Op::Eq => Value::from_bool(lhs == rhs),
+            Op::Ne => Value::from_bool(lhs != rhs),
+            Op::BitAnd => Value::Unsigned(lhs & rhs),
+            Op::BitXor => Value::Unsigned(lhs ^ rhs),
+            Op::BitOr => Value::Unsigned(lhs | rhs),
+            _ => unreachable!("{} is not a binary operator", op),
+        }
+    } else {
+        let (lhs, rhs) = (lhs.bits() as i64, rhs.bits() as i64);
+        match op {
+            Op::Add => Value::Signed(lhs.wrapping_add(rhs)),
+            Op::Sub => Value::Signed(lhs.wrapping_sub(rhs)),
+            Op::Mul => Value::Signed(lhs.wrapping_mul(rhs)),
+            Op::Div | Op::Rem if rhs == 0 => return Err("division by zero in #if".to_string()),
+            Op::Div => Value::Signed(lhs.wrapping_div(rhs)),
+            Op::Rem => Value::Signed(lhs.wrapping_rem(rhs)),
+            Op::Lt => Value::from_bool(lhs < rhs),
+            Op::Gt => Value::from_bool(lhs > rhs),
+            Op::Le => Value::from_bool(lhs <= rhs),
+            Op::Ge => Value::from_bool(lhs >= rhs),
+            Op::Eq => Value::from_bool(lhs == rhs),
+            Op::Ne => Value::from_bool(lhs != rhs),
+            Op::BitAnd => Value::Signed(lhs & rhs),
+            Op::BitXor => Value::Signed(lhs ^ rhs),
+            Op::BitOr => Value::Signed(lhs | rhs),
+            _ => unreachable!("{} is not a binary operator", op),
+        }
+    };
+    Ok(result)
+}
+
+/// Shifts keep the type of the left operand. Like GCC, a negative shift count shifts in the
+/// opposite direction, and shifting by the width of the type or more shifts out every bit.
+fn shift(op: Op, lhs: Value, rhs: Value) -> Value {
+    let count = match rhs {
+        Value::Signed(count) => count,
+        Value::Unsigned(count) => count.min(64) as i64,
+    };
+    let (is_left, count) = match (op, count < 0) {
+        (Op::Shl, false) | (Op::Shr, true) => (true, count.unsigned_abs()),
+        _ => (false, count.unsigned_abs()),
+    };
+    match lhs {
+        Value::Signed(value) if is_left => {
+            Value::Signed(if count >= 64 { 0 } else { value << count })
+        }
+        Value::Signed(value) => Value::Signed(value >> count.min(63)),
+        Value::Unsigned(value) => Value::Unsigned(match (is_left, count >= 64) {
+            (_, true) => 0,
+            (true, false) => value << count,
+            (false, false) => value >> count,
+        }),
+    }
+}
+
+/// Evaluates the pp-tokens of a macro-expanded `#if` expression, where
+/// `is_function_like` tells the names of function-like macros, which are left unexpanded
+/// without arguments. Every reduction step is explained in `steps`.
+pub fn evaluate(
+    input: &[PpToken],
+    is_function_like: &dyn Fn(&str) -> bool,
+    steps: &mut Vec<String>,
+) -> Result<Value, String> {
+    let tokens = tokenize(input, is_function_like)?;
+    if tokens.is_empty() {
+        return Err("#if with no expression".to_string());
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.conditional()?;
+    if parser.pos != parser.tokens.len() {
+        return Err("missing binary operator in expression".to_string());
+    }
+    eval(&expr, steps)
+}
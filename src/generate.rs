@@ -0,0 +1,302 @@
+//! Random but well-formed macro programs, to compare the explainer with a compiler on
+//! more idioms than the hand-written tests.
+
+/// A xorshift64* generator, so that a program is determined by its seed alone.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero.
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() >> 32) as usize % n
+    }
+
+    /// Whether an event of probability `1 / n` happens.
+    fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// A macro defined by the program so far.
+struct Macro {
+    name: String,
+    /// The named parameters, or `None` for an object-like macro.
+    params: Option<Vec<String>>,
+    is_variadic: bool,
+    /// Whether each named parameter is an operand of `##`, in which case the arguments
+    /// given to it must paste into valid tokens.
+    is_pasted: Vec<bool>,
+    /// Whether the expansion may contain commas, in which case it must not be given as a
+    /// named argument, where it would be split into several ones when rescanned.
+    has_commas: bool,
+}
+
+const WORDS: &[&str] = &["a", "b", "c", "x1", "y2", "0", "1", "42"];
+
+/// Literals whose contents look like macro names, arguments and escapes, which must be left
+/// as they are, except by `#`.
+const LITERALS: &[&str] = &[
+    r#""a, b)""#,
+    r#"L"M0(""#,
+    r#"u8"\"""#,
+    r"'\''",
+    r"'\\'",
+    r#"U'"'"#,
+    "\"é\tb\"",
+];
+
+/// The helpers that expand a macro one rescan later than usual.
+const DEFERRAL: &str = "\
+#define EMPTY()
+#define DEFER(id) id EMPTY()
+#define EXPAND(...) __VA_ARGS__
+";
+
+/// The versions of the grammar, each giving the same program for a seed as it always did,
+/// so that reported failures can be reproduced. Version 2 adds string and character
+/// literals to version 1.
+pub(crate) const VERSIONS: std::ops::RangeInclusive<u32> = 1..=2;
+
+/// Generates a program that defines object-like and function-like macros, with `#`, `##`,
+/// variadic parameters, self-references and deferred expansion, and invokes them.
+///
+/// Arguments are kept balanced and of the right number, and the operands of `##` paste
+/// into identifiers and numbers, so that a conforming preprocessor accepts the program.
+pub(crate) fn generate(version: u32, seed: u64) -> String {
+    let has_literals = version >= 2;
+    let mut rng = Rng::new(seed);
+    let mut macros: Vec<Macro> = Vec::new();
+    let mut source = DEFERRAL.to_string();
+    for i in 0..2 + rng.below(6) {
+        let name = format!("M{}", i);
+        let (definition, defined) = if rng.one_in(3) {
+            let body = body(&mut rng, &macros, &name, None, has_literals);
+            let defined = Macro {
+                name: name.clone(),
+                params: None,
+                is_variadic: false,
+                is_pasted: Vec::new(),
+                has_commas: body.has_commas,
+            };
+            (format!("#define {} {}", name, body.text), defined)
+        } else {
+            let params: Vec<String> = (0..rng.below(4)).map(|i| format!("p{}", i)).collect();
+            let is_variadic = rng.one_in(3);
+            let mut list = params.clone();
+            if is_variadic {
+                list.push("...".to_string());
+            }
+            let body = body(
+                &mut rng,
+                &macros,
+                &name,
+                Some((&params, is_variadic)),
+                has_literals,
+            );
+            let defined = Macro {
+                name: name.clone(),
+                params: Some(params),
+                is_variadic,
+                is_pasted: body.is_pasted,
+                has_commas: body.has_commas,
+            };
+            (
+                format!("#define {}({}) {}", name, list.join(", "), body.text),
+                defined,
+            )
+        };
+        source += &definition;
+        source += "\n";
+        macros.push(defined);
+    }
+    for _ in 0..1 + rng.below(5) {
+        let items: Vec<String> = (0..1 + rng.below(3))
+            .map(|_| top_level_item(&mut rng, &macros, has_literals))
+            .collect();
+        source += &items.join(" ");
+        source += ";\n";
+    }
+    source
+}
+
+struct Body {
+    text: String,
+    is_pasted: Vec<bool>,
+    has_commas: bool,
+}
+
+/// Generates the replacement list of `name`, which may invoke the macros defined before it
+/// and itself. `params` are the named parameters of a function-like macro and whether it is
+/// variadic.
+fn body(
+    rng: &mut Rng,
+    macros: &[Macro],
+    name: &str,
+    params: Option<(&[String], bool)>,
+    has_literals: bool,
+) -> Body {
+    let (names, is_variadic) = params.unwrap_or((&[], false));
+    let mut is_pasted = vec![false; names.len()];
+    let operand = |rng: &mut Rng, is_pasted: &mut Vec<bool>| {
+        if !names.is_empty() && rng.one_in(2) {
+            let i = rng.below(names.len());
+            is_pasted[i] = true;
+            names[i].clone()
+        } else {
+            rng.pick(&["a", "b", "M", "_", "1"]).to_string()
+        }
+    };
+    let mut items = Vec::new();
+    let mut invocations = 0;
+    let mut has_commas = false;
+    for _ in 0..rng.below(6) {
+        // Literals are drawn apart, so that the other choices are the same as in version 1.
+        if has_literals && rng.one_in(9) {
+            items.push(rng.pick(LITERALS).to_string());
+            continue;
+        }
+        let item = match rng.below(8) {
+            0 | 1 if !names.is_empty() => rng.pick(names).clone(),
+            2 if is_variadic => {
+                has_commas = true;
+                "__VA_ARGS__".to_string()
+            }
+            3 if !names.is_empty() => format!("#{}", rng.pick(names)),
+            4 => {
+                let left = operand(rng, &mut is_pasted);
+                let right = operand(rng, &mut is_pasted);
+                format!("{} ## {}", left, right)
+            }
+            5 | 6 if invocations < 2 && !macros.is_empty() => {
+                invocations += 1;
+                let callee = rng.pick(macros);
+                has_commas |= callee.has_commas;
+                invocation(rng, callee, &|rng, _| body_argument(rng, names))
+            }
+            7 if invocations < 2 => {
+                invocations += 1;
+                // A self-reference, which is not replaced again.
+                match params {
+                    None => name.to_string(),
+                    Some(_) => {
+                        has_commas |= names.len() > 1;
+                        format!("{}({})", name, vec!["a"; names.len()].join(", "))
+                    }
+                }
+            }
+            _ => rng.pick(WORDS).to_string(),
+        };
+        items.push(item);
+    }
+    Body {
+        text: items.join(" "),
+        is_pasted,
+        has_commas,
+    }
+}
+
+/// Generates an argument for an invocation in a body, from the parameters of the body, whose
+/// arguments have no commas once expanded.
+fn body_argument(rng: &mut Rng, params: &[String]) -> String {
+    match rng.below(3) {
+        0 if !params.is_empty() => rng.pick(params).clone(),
+        1 => String::new(),
+        _ => rng.pick(WORDS).to_string(),
+    }
+}
+
+/// Generates an invocation of `callee` with `argument` making the arguments that are not
+/// pasted, given whether they may expand to commas, as the variable arguments may. A
+/// function-like macro is sometimes left without arguments, or deferred.
+fn invocation(
+    rng: &mut Rng,
+    callee: &Macro,
+    argument: &dyn Fn(&mut Rng, bool) -> String,
+) -> String {
+    let Some(params) = &callee.params else {
+        return callee.name.clone();
+    };
+    if rng.one_in(8) {
+        return callee.name.clone();
+    }
+    let mut arguments: Vec<String> = params
+        .iter()
+        .zip(&callee.is_pasted)
+        .map(|(_, &is_pasted)| match is_pasted {
+            true => pasted_argument(rng),
+            false => argument(rng, false),
+        })
+        .collect();
+    if callee.is_variadic {
+        for _ in 0..rng.below(3) {
+            arguments.push(argument(rng, true));
+        }
+    }
+    let arguments = arguments.join(", ");
+    match rng.one_in(4) {
+        true => format!("EXPAND(DEFER({})({}))", callee.name, arguments),
+        false => format!("{}({})", callee.name, arguments),
+    }
+}
+
+/// Generates an argument for a parameter that is an operand of `##`, which pastes into an
+/// identifier or a number.
+fn pasted_argument(rng: &mut Rng) -> String {
+    match rng.below(4) {
+        0 => String::new(),
+        1 => format!("{} {}", rng.pick(WORDS), rng.pick(WORDS)),
+        _ => rng.pick(&["a", "b", "M", "_", "1"]).to_string(),
+    }
+}
+
+/// Generates an item of a line outside of the definitions: a word, or an invocation whose
+/// arguments may invoke macros in turn.
+fn top_level_item(rng: &mut Rng, macros: &[Macro], has_literals: bool) -> String {
+    if rng.one_in(4) {
+        return rng.pick(WORDS).to_string();
+    }
+    let callee = rng.pick(macros);
+    invocation(rng, callee, &|rng, may_have_commas| {
+        top_level_argument(rng, macros, may_have_commas, has_literals)
+    })
+}
+
+fn top_level_argument(
+    rng: &mut Rng,
+    macros: &[Macro],
+    may_have_commas: bool,
+    has_literals: bool,
+) -> String {
+    if has_literals && rng.one_in(5) {
+        return rng.pick(LITERALS).to_string();
+    }
+    let callees: Vec<&Macro> = macros
+        .iter()
+        .filter(|callee| may_have_commas || !callee.has_commas)
+        .collect();
+    match rng.below(4) {
+        0 => String::new(),
+        1 if !callees.is_empty() => {
+            // Nested invocations are not themselves nested further, to bound the size.
+            let callee = rng.pick(&callees);
+            invocation(rng, callee, &|rng, _| rng.pick(WORDS).to_string())
+        }
+        2 => format!("{} {}", rng.pick(WORDS), rng.pick(WORDS)),
+        _ => rng.pick(WORDS).to_string(),
+    }
+}
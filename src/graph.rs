@@ -0,0 +1,179 @@
+use itertools::Itertools;
+
+use crate::trace::{Event, Node};
+
+/// What a vertex of the expansion graph stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VertexKind {
+    Invocation,
+    Step,
+}
+
+/// The expansion tree of one invocation, flattened into vertices and labeled edges.
+#[derive(Debug, Default)]
+struct Graph {
+    vertices: Vec<(VertexKind, Vec<String>)>,
+    edges: Vec<(usize, usize, &'static str)>,
+}
+
+impl Graph {
+    fn new(invocation: &Node) -> Self {
+        let mut graph = Graph::default();
+        graph.add(invocation);
+        graph
+    }
+
+    /// Adds a step of the trace and the steps it is made of, returning its vertex.
+    fn add(&mut self, node: &Node) -> usize {
+        let id = self.vertices.len();
+        let kind = match is_invocation(node) {
+            true => VertexKind::Invocation,
+            false => VertexKind::Step,
+        };
+        self.vertices.push((kind, label(&node.event)));
+        for child in &node.children {
+            let (edge, steps) = match child.event {
+                Event::Prescan { .. } => ("prescan", &child.children[..]),
+                Event::Rescan { .. } => ("rescan", &child.children[..]),
+                _ => ("substitution", std::slice::from_ref(child)),
+            };
+            for step in steps {
+                self.edges.push((id, self.vertices.len(), edge));
+                self.add(step);
+            }
+        }
+        id
+    }
+}
+
+fn is_invocation(node: &Node) -> bool {
+    matches!(
+        node.event,
+        Event::ObjectExpansion { .. } | Event::FunctionExpansion { .. }
+    )
+}
+
+/// The lines shown in the vertex of a step.
+fn label(event: &Event) -> Vec<String> {
+    match event {
+        Event::ObjectExpansion { site, replacement } => {
+            vec![site.name.clone(), format!("=> {}", replacement)]
+        }
+        Event::FunctionExpansion {
+            site,
+            bindings,
+            result,
+        } => {
+            let mut lines = vec![site.name.clone()];
+            lines.extend(bindings.iter().map(|binding| binding.to_string()));
+            lines.push(format!("=> {}", result));
+            lines
+        }
+        Event::PrescanSkipped { param, arg, .. } => {
+            vec![
+                format!("{} is not pre-expanded", param),
+                format!("`{}`", arg),
+            ]
+        }
+        Event::SelfReference { site, .. } => vec![format!("{} is painted blue", site.name)],
+        Event::Stringification {
+            operand, result, ..
+        } => vec![format!("# `{}`", operand), format!("=> {}", result)],
+        Event::Pasting {
+            lhs, rhs, result, ..
+        } => vec![format!("{} ## {}", lhs, rhs), format!("=> {}", result)],
+        Event::VaOpt {
+            va_opt,
+            replacement,
+            ..
+        } => vec![
+            va_opt.clone(),
+            format!("=> {}", replacement.as_deref().unwrap_or("")),
+        ],
+        Event::CommaElision {
+            param,
+            variable_arguments,
+            ..
+        } => vec![
+            format!(", ## {}", param),
+            match variable_arguments {
+                Some(_) => "comma kept".to_string(),
+                None => "comma swallowed".to_string(),
+            },
+        ],
+        event => vec![event.to_string()],
+    }
+}
+
+/// Returns the top-level macro invocations of a trace, in order, including those in
+/// `#if` conditions and in included files.
+pub fn invocations(trace: &[Node]) -> Vec<&Node> {
+    let mut result = Vec::new();
+    for node in trace {
+        if is_invocation(node) {
+            result.push(node);
+        } else {
+            result.extend(invocations(&node.children));
+        }
+    }
+    result
+}
+
+/// Renders the expansion tree of an invocation as a Graphviz DOT digraph. Edges are labeled
+/// `prescan`, `substitution` or `rescan`.
+pub fn to_dot(invocation: &Node) -> String {
+    let graph = Graph::new(invocation);
+    let mut dot = String::from("digraph expansion {\n    node [fontname=\"monospace\"];\n");
+    for (id, (kind, lines)) in graph.vertices.iter().enumerate() {
+        let shape = match kind {
+            VertexKind::Invocation => "box",
+            VertexKind::Step => "ellipse",
+        };
+        let label: String = lines
+            .iter()
+            .map(|line| format!("{}\\l", escape_dot(line)))
+            .collect();
+        dot += &format!("    n{} [shape={}, label=\"{}\"];\n", id, shape, label);
+    }
+    for (from, to, label) in &graph.edges {
+        dot += &format!("    n{} -> n{} [label=\"{}\"];\n", from, to, label);
+    }
+    dot += "}\n";
+    dot
+}
+
+/// Renders the expansion tree of an invocation as a Mermaid flowchart. Edges are labeled
+/// `prescan`, `substitution` or `rescan`.
+pub fn to_mermaid(invocation: &Node) -> String {
+    let graph = Graph::new(invocation);
+    let mut mermaid = String::from("flowchart TD\n");
+    for (id, (kind, lines)) in graph.vertices.iter().enumerate() {
+        let label = lines.iter().map(|line| escape_mermaid(line)).join("<br/>");
+        let (open, close) = match kind {
+            VertexKind::Invocation => ("[", "]"),
+            VertexKind::Step => ("([", "])"),
+        };
+        mermaid += &format!("    n{}{}\"{}\"{}\n", id, open, label, close);
+    }
+    for (from, to, label) in &graph.edges {
+        mermaid += &format!("    n{} -->|{}| n{}\n", from, label, to);
+    }
+    mermaid
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escapes the characters with a special meaning in Mermaid labels as entity codes.
+fn escape_mermaid(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '"' => "#quot;".to_string(),
+            '#' => "#35;".to_string(),
+            '<' => "#lt;".to_string(),
+            '>' => "#gt;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,161 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::graph::invocations;
+use crate::preprocessor::Preprocessed;
+use crate::trace::{Event, Node};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+pre, code { font-family: monospace; }
+pre { background: #f6f8fa; padding: 1em; overflow-x: auto; }
+a.invocation { background: #fff3b0; color: inherit; text-decoration: none; border-radius: 3px; }
+a.invocation:hover { background: #ffe066; }
+section.explanation { display: none; border-left: 4px solid #ffe066; padding-left: 1em; }
+section.explanation:target { display: block; }
+ul.trace { list-style: none; padding-left: 1.5em; }
+li.prescan > details > summary { color: #555; }
+.result { font-weight: bold; }
+.error { color: #c00; }
+.warning { color: #b60; }
+.note { color: #555; }
+";
+
+/// Renders a self-contained HTML report of the preprocessing of `source`, read from `file`
+/// unless it is the standard input. The report needs no network access nor script. The
+/// macro invocations of the source are highlighted, and clicking one shows its expansion
+/// step by step, with collapsible prescans and the final result. The output and the
+/// diagnostics follow.
+pub fn to_html(source: &str, file: Option<&Path>, result: &Preprocessed) -> String {
+    let mut nodes = Vec::new();
+    written_invocations(invocations(&result.trace), &mut nodes);
+    let invocations: Vec<&Node> = nodes
+        .into_iter()
+        .filter(|node| node.event.site().unwrap().invocation.file.as_deref() == file)
+        .collect();
+    // The invocations written in the source, by line and byte offset in the line.
+    let mut positions = BTreeMap::new();
+    for (id, node) in invocations.iter().enumerate() {
+        let site = node.event.site().unwrap();
+        positions
+            .entry((site.invocation.line, site.invocation.column - 1))
+            .or_insert((id, site.name.len()));
+    }
+
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html += "<title>Macro expansion report</title>\n";
+    html += &format!("<style>{}</style>\n</head>\n<body>\n", STYLE);
+    html += "<h1>Source</h1>\n<pre class=\"source\">";
+    for (line, text) in source.lines().enumerate() {
+        let mut offset = 0;
+        for (&(_, column), &(id, len)) in positions.range((line + 1, 0)..(line + 2, 0)) {
+            if column < offset || column + len > text.len() {
+                continue;
+            }
+            html += &escape(&text[offset..column]);
+            html += &format!(
+                "<a class=\"invocation\" href=\"#invocation-{}\">{}</a>",
+                id,
+                escape(&text[column..column + len])
+            );
+            offset = column + len;
+        }
+        html += &escape(&text[offset..]);
+        html += "\n";
+    }
+    html += "</pre>\n";
+
+    for (id, node) in invocations.iter().enumerate() {
+        let site = node.event.site().unwrap();
+        html += &format!(
+            "<section class=\"explanation\" id=\"invocation-{}\">\n<h2>{} at {}</h2>\n",
+            id,
+            escape(&site.name),
+            site.invocation
+        );
+        html += &format!(
+            "<p>Final result: <code class=\"result\">{}</code></p>\n",
+            escape(&final_result(node))
+        );
+        html += "<ul class=\"trace\">\n";
+        write_node(&mut html, node);
+        html += "</ul>\n</section>\n";
+    }
+
+    if !result.diagnostics.is_empty() {
+        html += "<h1>Diagnostics</h1>\n<ul>\n";
+        for diagnostic in &result.diagnostics {
+            html += &format!(
+                "<li class=\"{}\">{}</li>\n",
+                diagnostic.severity,
+                escape(&diagnostic.to_string())
+            );
+        }
+        html += "</ul>\n";
+    }
+    html += &format!(
+        "<h1>Preprocessed code</h1>\n<pre class=\"output\">{}</pre>\n",
+        escape(&result.output)
+    );
+    html += "</body>\n</html>\n";
+    html
+}
+
+/// Collects the invocations together with those in their arguments, such as `A()` in
+/// `F(A())`, which are written in the source as well. Invocations found by rescanning come
+/// from macro bodies instead, and are left out.
+fn written_invocations<'a>(nodes: Vec<&'a Node>, result: &mut Vec<&'a Node>) {
+    for node in nodes {
+        result.push(node);
+        for child in &node.children {
+            if let Event::Prescan { .. } = child.event {
+                written_invocations(invocations(&child.children), result);
+            }
+        }
+    }
+}
+
+/// The tokens that replace an invocation once no more macros can be expanded.
+fn final_result(invocation: &Node) -> String {
+    let rescan = invocation
+        .children
+        .iter()
+        .find_map(|child| match &child.event {
+            Event::Rescan { result, .. } => Some(result),
+            _ => None,
+        });
+    match (&invocation.event, rescan) {
+        (_, Some(result)) => result.clone(),
+        (Event::ObjectExpansion { replacement, .. }, None) => replacement.clone(),
+        (Event::FunctionExpansion { result, .. }, None) => result.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Writes a step as a list item. Steps made of other steps can be collapsed, and prescans
+/// start collapsed.
+fn write_node(html: &mut String, node: &Node) {
+    let message = escape(&node.event.to_string());
+    if node.children.is_empty() {
+        *html += &format!("<li>{}</li>\n", message);
+        return;
+    }
+    let (class, open) = match node.event {
+        Event::Prescan { .. } => ("prescan", ""),
+        _ => ("step", " open"),
+    };
+    *html += &format!(
+        "<li class=\"{}\"><details{}><summary>{}</summary>\n<ul class=\"trace\">\n",
+        class, open, message
+    );
+    for child in &node.children {
+        write_node(html, child);
+    }
+    *html += "</ul>\n</details></li>\n";
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Supplies the contents of included files, so that headers can come from the disk or from
+/// memory.
+pub trait FileProvider {
+    /// Returns the contents of the file at `path`, or `None` if there is no such file.
+    fn read_file(&self, path: &Path) -> Option<String>;
+}
+
+/// Reads included files from the file system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileSystem;
+
+impl FileProvider for FileSystem {
+    fn read_file(&self, path: &Path) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+}
+
+/// A virtual file system holding every file in memory.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryFiles {
+    files: HashMap<PathBuf, String>,
+}
+
+impl InMemoryFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file(&mut self, path: impl Into<PathBuf>, contents: impl Into<String>) {
+        self.files.insert(path.into(), contents.into());
+    }
+}
+
+impl FileProvider for InMemoryFiles {
+    fn read_file(&self, path: &Path) -> Option<String> {
+        self.files.get(path).cloned()
+    }
+}
+
+/// Where a header is looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderKind {
+    /// `#include "name"` searches the directory of the including file first.
+    Quoted,
+    /// `#include <name>` only searches the include directories.
+    Angled,
+}
+
+/// The directories searched by `#include`, in the same order as GCC: for the quoted form,
+/// the directory of the including file; then the user directories (`-I`); then the system
+/// directories (`-isystem`).
+#[derive(Debug, Default, Clone)]
+pub struct IncludePaths {
+    pub user: Vec<PathBuf>,
+    pub system: Vec<PathBuf>,
+}
+
+impl IncludePaths {
+    /// Lists the candidate paths of a header in search order.
+    pub fn candidates(&self, name: &str, kind: HeaderKind, current_dir: &Path) -> Vec<PathBuf> {
+        if Path::new(name).is_absolute() {
+            return vec![PathBuf::from(name)];
+        }
+        let current_dir = (kind == HeaderKind::Quoted).then_some(current_dir);
+        current_dir
+            .into_iter()
+            .chain(self.user.iter().map(PathBuf::as_path))
+            .chain(self.system.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .collect()
+    }
+}
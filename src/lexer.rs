@@ -1,27 +1,34 @@
 use logos::Logos;
-use nom::{
-    branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::multispace1,
-    combinator::map,
-    sequence::{pair, preceded, terminated, tuple},
-    IResult,
-};
-use std::collections::HashMap;
-
-#[derive(Logos, Debug, PartialEq)]
-enum Token {
-    #[regex(r"[\p{XID_Start}_]\p{XID_Continue}*")]
+use std::{collections::BTreeSet, path::Path, rc::Rc};
+
+use crate::diagnostic::Span;
+
+/// The kinds of preprocessing tokens of C17 6.4, together with the whitespace separating
+/// them and a few kinds that only appear during macro expansion.
+#[derive(Logos, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    #[regex(r"[\p{XID_Start}_$][\p{XID_Continue}$]*")]
     Identifier,
 
-    #[token("\r\n")]
-    #[token("\r")]
-    #[token("\n")]
-    LineTerminator,
+    /// A preprocessing number, which is more permissive than integer and floating constants.
+    #[regex(r"\.?[0-9]([0-9a-zA-Z_.]|[eEpP][+-])*")]
+    Number,
+
+    /// A character constant. Unlike strings, it cannot have the `u8` prefix before C23.
+    #[regex(r#"[uUL]?'([^'\\\n]|\\[^\n])*'"#)]
+    CharLiteral,
+
+    #[regex(r#"(u8|[uUL])?"([^"\\\n]|\\[^\n])*""#)]
+    StringLiteral,
 
     #[token("#")]
+    #[token("%:")]
     Hash,
 
+    #[token("##")]
+    #[token("%:%:")]
+    HashHash,
+
     #[token("(")]
     LParen,
 
@@ -31,6 +38,416 @@ enum Token {
     #[token(",")]
     Comma,
 
-    #[regex(r"[ \t]+", logos::skip)]
+    #[token("...")]
+    Ellipsis,
+
+    #[token("[")]
+    #[token("]")]
+    #[token("{")]
+    #[token("}")]
+    #[token(".")]
+    #[token("->")]
+    #[token("++")]
+    #[token("--")]
+    #[token("&")]
+    #[token("*")]
+    #[token("+")]
+    #[token("-")]
+    #[token("~")]
+    #[token("!")]
+    #[token("/")]
+    #[token("%")]
+    #[token("<<")]
+    #[token(">>")]
+    #[token("<")]
+    #[token(">")]
+    #[token("<=")]
+    #[token(">=")]
+    #[token("==")]
+    #[token("!=")]
+    #[token("^")]
+    #[token("|")]
+    #[token("&&")]
+    #[token("||")]
+    #[token("?")]
+    #[token(":")]
+    #[token("::")]
+    #[token(";")]
+    #[token("=")]
+    #[token("*=")]
+    #[token("/=")]
+    #[token("%=")]
+    #[token("+=")]
+    #[token("-=")]
+    #[token("<<=")]
+    #[token(">>=")]
+    #[token("&=")]
+    #[token("^=")]
+    #[token("|=")]
+    #[token("<:")]
+    #[token(":>")]
+    #[token("<%")]
+    #[token("%>")]
+    Punctuator,
+
+    /// A header name such as `<stdio.h>`, which is only lexed right after `#include`.
+    HeaderName,
+
+    /// A character that cannot start any other token, such as `@`, or a literal missing its
+    /// closing quote, which takes the rest of the line as in GCC.
+    #[regex(r#"[uUL]?'([^'\\\n]|\\[^\n])*"#)]
+    #[regex(r#"(u8|[uUL])?"([^"\\\n]|\\[^\n])*"#)]
+    Other,
+
+    #[regex(r"[ \t\x0B\x0C]+")]
     Whitespace,
+
+    /// A comment, which is equivalent to a single space.
+    #[regex(r"//[^\n]*")]
+    #[token("/*", block_comment)]
+    Comment,
+
+    #[token("\r\n")]
+    #[token("\r")]
+    #[token("\n")]
+    LineTerminator,
+
+    /// Marks the start of a macro expansion or of a substituted argument. It carries the
+    /// whitespace of the macro name or the parameter, so that the output is spaced like
+    /// GCC's.
+    Padding,
+
+    /// Marks the end of a macro expansion or of a substituted argument, where a space is
+    /// printed if the tokens on both sides would otherwise be read as one.
+    AvoidPaste,
+
+    /// Stands for an empty argument next to `##` until pasting is done.
+    Placemarker,
+}
+
+fn block_comment(lexer: &mut logos::Lexer<Token>) -> bool {
+    match lexer.remainder().find("*/") {
+        Some(end) => {
+            lexer.bump(end + 2);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The names of the macros whose expansions produced a token. A token naming a macro in its
+/// own hide set is never expanded, which is called being "painted blue".
+pub type HideSet = Rc<BTreeSet<Rc<str>>>;
+
+/// A preprocessing token together with where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PpToken {
+    pub kind: Token,
+    pub text: Rc<str>,
+    /// Whether whitespace precedes the token.
+    pub has_space: bool,
+    /// Whether the token is the first one on its line in the source.
+    pub is_line_start: bool,
+    /// The file the token was read from, if any.
+    pub file: Option<Rc<Path>>,
+    pub line: usize,
+    pub column: usize,
+    pub hide_set: HideSet,
+}
+
+impl PpToken {
+    pub fn new(kind: Token, text: &str) -> Self {
+        Self {
+            kind,
+            text: text.into(),
+            has_space: false,
+            is_line_start: false,
+            file: None,
+            line: 0,
+            column: 0,
+            hide_set: HideSet::default(),
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    pub fn is_padding(&self) -> bool {
+        matches!(self.kind, Token::Padding | Token::AvoidPaste)
+    }
+
+    pub fn is_identifier(&self, name: &str) -> bool {
+        self.kind == Token::Identifier && &*self.text == name
+    }
+}
+
+/// Removes every backslash-newline sequence. Each returned segment records that the
+/// spliced text starting at an offset comes from the given line and column.
+fn splice_lines(input: &str) -> (String, Vec<(usize, usize, usize)>) {
+    let mut text = String::with_capacity(input.len());
+    let mut segments = vec![(0, 1, 1)];
+    let mut line = 1;
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' && chars.next_if_eq(&'\n').is_some() {
+            line += 1;
+            segments.push((text.len(), line, 1));
+            continue;
+        }
+        text.push(ch);
+        if ch == '\n' {
+            line += 1;
+            segments.push((text.len(), line, 1));
+        }
+    }
+    (text, segments)
+}
+
+/// Splits the input into preprocessing tokens. Whitespace, comments and line terminators
+/// are not returned, but recorded in `has_space` and `is_line_start` of the next token.
+/// Every token records that it comes from `file`.
+pub fn tokenize(input: &str, file: Option<&Path>) -> Vec<PpToken> {
+    let file: Option<Rc<Path>> = file.map(Rc::from);
+    let (text, segments) = splice_lines(input);
+    let mut tokens = Vec::new();
+    let mut has_space = false;
+    let mut is_line_start = true;
+    let mut lexer = Token::lexer(&text);
+    while let Some(kind) = lexer.next() {
+        match kind.unwrap_or(Token::Other) {
+            Token::Whitespace | Token::Comment => has_space = true,
+            Token::LineTerminator => {
+                has_space = false;
+                is_line_start = true;
+            }
+            kind => {
+                let offset = lexer.span().start;
+                let segment = segments.partition_point(|(start, _, _)| *start <= offset) - 1;
+                let (start, line, column) = segments[segment];
+                tokens.push(PpToken {
+                    kind,
+                    text: lexer.slice().into(),
+                    has_space,
+                    is_line_start,
+                    file: file.clone(),
+                    line,
+                    column: column + offset - start,
+                    hide_set: HideSet::default(),
+                });
+                has_space = false;
+                is_line_start = false;
+                if let Some(len) = header_name_len(&tokens, lexer.remainder()) {
+                    let start = lexer.span().end;
+                    lexer.bump(len);
+                    let spaces = len - text[start..start + len].trim_start().len();
+                    let offset = start + spaces;
+                    let segment = segments.partition_point(|(start, _, _)| *start <= offset) - 1;
+                    let (start, line, column) = segments[segment];
+                    tokens.push(PpToken {
+                        kind: Token::HeaderName,
+                        text: text[offset..offset + len - spaces].into(),
+                        has_space: spaces > 0,
+                        is_line_start: false,
+                        file: file.clone(),
+                        line,
+                        column: column + offset - start,
+                        hide_set: HideSet::default(),
+                    });
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// Returns the length of the whitespace and the header name that follow `#include`, if
+/// the last tokens are that directive. The header name is taken from the text rather than
+/// lexed as usual, since `//` in `<x//y.h>` does not start a comment.
+fn header_name_len(tokens: &[PpToken], remainder: &str) -> Option<usize> {
+    let [.., hash, name] = tokens else {
+        return None;
+    };
+    if hash.kind != Token::Hash || !hash.is_line_start || &*name.text != "include" {
+        return None;
+    }
+    let header_name = remainder.trim_start_matches([' ', '\t', '\x0B', '\x0C']);
+    let close = match header_name.chars().next()? {
+        '<' => '>',
+        '"' => '"',
+        _ => return None,
+    };
+    let end = header_name[1..].find(['\n', '\r', close])? + 1;
+    if !header_name[end..].starts_with(close) {
+        return None;
+    }
+    Some(remainder.len() - header_name.len() + end + 1)
+}
+
+/// Returns the quote of a literal missing its closing quote.
+pub fn missing_quote(token: &PpToken) -> Option<char> {
+    if token.kind != Token::Other {
+        return None;
+    }
+    let quote = token
+        .text
+        .trim_start_matches(['u', '8', 'U', 'L'])
+        .chars()
+        .next();
+    quote.filter(|quote| matches!(quote, '\'' | '"'))
+}
+
+/// Lexes the result of `##`, which must be exactly one preprocessing token.
+pub fn lex_single(text: &str) -> Option<Token> {
+    let mut lexer = Token::lexer(text);
+    let kind = lexer.next()?.ok()?;
+    let is_token = !matches!(
+        kind,
+        Token::Whitespace | Token::Comment | Token::LineTerminator
+    );
+    (is_token && lexer.next().is_none()).then_some(kind)
+}
+
+/// Whether a space is needed between two adjacent tokens so that they are not read back as
+/// a different token, following `cpp_avoid_paste` of GCC.
+fn avoids_paste(lhs: &PpToken, rhs: &PpToken) -> bool {
+    let c = match rhs.kind {
+        Token::Hash
+        | Token::HashHash
+        | Token::LParen
+        | Token::RParen
+        | Token::Comma
+        | Token::Ellipsis
+        | Token::Punctuator => rhs.text.chars().next(),
+        _ => None,
+    };
+    let is_literal_or_name = matches!(
+        rhs.kind,
+        Token::Identifier | Token::CharLiteral | Token::StringLiteral
+    );
+    match (lhs.kind, &*lhs.text) {
+        (
+            Token::Punctuator,
+            "=" | "!" | ">" | "<" | "+" | "-" | "*" | "/" | "%" | "&" | "|" | "^" | ">>" | "<<",
+        ) if c == Some('=') => true,
+        (Token::Punctuator, ">") => c == Some('>'),
+        (Token::Punctuator, "<") => matches!(c, Some('<' | '%' | ':')),
+        (Token::Punctuator, "+") => c == Some('+'),
+        (Token::Punctuator, "-") => matches!(c, Some('-' | '>')),
+        (Token::Punctuator, "/") => matches!(c, Some('/' | '*')),
+        (Token::Punctuator, "%") => matches!(c, Some(':' | '%')),
+        (Token::Punctuator, "&") => c == Some('&'),
+        (Token::Punctuator, "|") => c == Some('|'),
+        (Token::Punctuator, ":") => matches!(c, Some(':' | '>')),
+        (Token::Punctuator, "->") => c == Some('*'),
+        (Token::Punctuator, ".") => matches!(c, Some('.' | '%')) || rhs.kind == Token::Number,
+        (Token::Hash, _) => matches!(c, Some('#' | '%')),
+        (Token::Identifier, _) => is_literal_or_name,
+        (Token::Number, _) => {
+            is_literal_or_name && rhs.kind != Token::StringLiteral
+                || rhs.kind == Token::Number
+                || matches!(c, Some('.' | '+' | '-'))
+        }
+        (Token::Other, text) => text.starts_with('\\') && rhs.kind == Token::Identifier,
+        _ => false,
+    }
+}
+
+/// Spells tokens with a single space wherever there was whitespace or where the tokens would
+/// otherwise be pasted together, as in explanations.
+pub fn spell(tokens: &[PpToken]) -> String {
+    spell_marked(tokens, &[]).0
+}
+
+/// Spells tokens like `spell`, and returns with the spelling the length of the spelling of
+/// the tokens before each index of `marks`, which are in ascending order.
+pub fn spell_marked<'a>(
+    tokens: impl IntoIterator<Item = &'a PpToken>,
+    marks: &[usize],
+) -> (String, Vec<usize>) {
+    let (text, offsets) = print(tokens, false, marks);
+    let trimmed = text.len() - text.trim_start().len();
+    let text = text.trim().to_string();
+    let offsets = offsets
+        .into_iter()
+        .map(|offset| offset.saturating_sub(trimmed).min(text.len()))
+        .collect();
+    (text, offsets)
+}
+
+/// Prints the preprocessed tokens like `cc -E -P`: every source line that is not part of
+/// a macro invocation starts a new line indented to its original column, and tokens are
+/// separated by a space where the source had whitespace or where they would otherwise be
+/// pasted together.
+pub fn render(tokens: &[PpToken]) -> String {
+    print(tokens, true, &[]).0
+}
+
+/// Prints tokens, and returns with the text its length before each index of `marks`.
+fn print<'a>(
+    tokens: impl IntoIterator<Item = &'a PpToken>,
+    keeps_lines: bool,
+    marks: &[usize],
+) -> (String, Vec<usize>) {
+    let mut result = String::new();
+    let mut offsets = Vec::with_capacity(marks.len());
+    let mut is_printed = false;
+    let mut prev: Option<&PpToken> = None;
+    // The whitespace of the token that started the pending padding, if any.
+    let mut source: Option<bool> = None;
+    let mut avoid_paste = false;
+    for (i, token) in tokens.into_iter().enumerate() {
+        while marks.get(offsets.len()) == Some(&i) {
+            offsets.push(result.len());
+        }
+        if keeps_lines && token.is_line_start {
+            if is_printed {
+                result.push('\n');
+            }
+            is_printed = true;
+            prev = None;
+            source = None;
+            result.extend(std::iter::repeat_n(' ', token.column.saturating_sub(2)));
+        }
+        match token.kind {
+            Token::Padding => {
+                avoid_paste = true;
+                if source.is_none() {
+                    source = Some(token.has_space);
+                }
+            }
+            Token::AvoidPaste => {
+                avoid_paste = true;
+                if source == Some(false) {
+                    source = None;
+                }
+            }
+            Token::Placemarker => {}
+            _ => {
+                let needs_space = if avoid_paste {
+                    source.unwrap_or(token.has_space)
+                        || prev.is_some_and(|prev| avoids_paste(prev, token))
+                        || (prev.is_none() && token.kind == Token::Hash)
+                } else {
+                    token.has_space
+                };
+                if needs_space {
+                    result.push(' ');
+                }
+                result.push_str(&token.text);
+                avoid_paste = false;
+                source = None;
+                prev = Some(token);
+            }
+        }
+    }
+    offsets.resize(marks.len(), result.len());
+    if is_printed {
+        result.push('\n');
+    }
+    (result, offsets)
 }
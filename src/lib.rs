@@ -1,4 +1,26 @@
+mod check;
+mod debug;
+mod diagnostic;
+mod expr;
+#[cfg(test)]
+mod generate;
+mod graph;
+mod html;
+mod include;
+mod lexer;
+mod minimize;
 mod parser;
 mod preprocessor;
+mod rewrite;
+mod trace;
 
-pub use preprocessor::preprocess;
+pub use check::{compare, standardize, Mismatch};
+pub use debug::{Debugger, Replacement};
+pub use diagnostic::{Diagnostic, Severity, Span};
+pub use graph::{invocations, to_dot, to_mermaid};
+pub use html::to_html;
+pub use include::{FileProvider, FileSystem, InMemoryFiles};
+pub use minimize::minimize;
+pub use preprocessor::{preprocess, KeptGroup, MacroDefinition, MacroPreprocessor, Preprocessed};
+pub use rewrite::{rewrite_view, Rewrite};
+pub use trace::{Binding, Event, Node, Site};
@@ -1,10 +1,609 @@
-use std::io::Read;
-
-fn main() {
-    // Read lines until EOF
-    let mut input = String::new();
-    let line = String::new();
-    std::io::stdin().read_to_string(&mut input).unwrap();
-    let result = c_macro_explainer::preprocess(&input);
-    println!("Preprocessed code:\n{}", result);
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, ExitCode, Stdio};
+
+use c_macro_explainer::{Diagnostic, MacroPreprocessor, Node, Preprocessed, Severity};
+
+mod debugger;
+mod repl;
+
+const USAGE: &str = "\
+Usage: c-macro-explainer [options] [input.c...]
+       c-macro-explainer --repl [options]
+       c-macro-explainer --debug [options] input.c
+The standard input is read if no input is given, or for `-`.
+Options:
+  -D NAME[=VALUE]       define a macro, as `1` if no value is given
+  -U NAME               undefine a macro
+  -I DIR                add a directory to search for included files
+  -isystem DIR          add a directory to search for system headers
+  -o FILE               write to FILE instead of the standard output
+  --format FORMAT       text, json, dot, mermaid, html or rewrite; default is text
+  --invocation N        the top-level invocation drawn by dot and mermaid; default is 1
+  --trace, --no-trace   whether the text format explains the expansion; default is on
+  --explain-only        print only the explanation in the text format
+  --output-only         print only the preprocessed code in the text format
+  --check-against CC    compare the output with `CC -E -P`, such as cc, gcc or clang,
+                        and explain the lines that differ
+  --minimize            reduce the input while its output still differs from the
+                        compiler of --check-against, cc by default, and print the
+                        reduced input
+  --help                show this message
+Other options of cc, such as -std=c11 or -Wall, are ignored with a warning.
+The exit status is 1 if an error is reported or the output differs, as for cc.";
+
+/// The options of cc that are ignored, but whose value may be the next argument.
+const IGNORED_WITH_VALUE: &[&str] = &[
+    "-x",
+    "-MF",
+    "-MT",
+    "-MQ",
+    "-include",
+    "-imacros",
+    "-idirafter",
+    "-iquote",
+    "-iprefix",
+    "-isysroot",
+    "-Xpreprocessor",
+    "-Xassembler",
+    "-Xlinker",
+    "--param",
+];
+
+/// How the result is printed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// The trace indented as a tree, followed by the preprocessed code.
+    Text,
+    /// The output, macro table, diagnostics and trace as a JSON array with an object for
+    /// each input.
+    Json,
+    /// The expansion tree of one top-level invocation as a Graphviz digraph.
+    Dot,
+    /// The expansion tree of one top-level invocation as a Mermaid flowchart.
+    Mermaid,
+    /// A self-contained page where each invocation in the source can be clicked to see its
+    /// expansion.
+    Html,
+    /// Each line as it is rewritten by one invocation after another.
+    Rewrite,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "dot" => Some(Format::Dot),
+            "mermaid" => Some(Format::Mermaid),
+            "html" => Some(Format::Html),
+            "rewrite" => Some(Format::Rewrite),
+            _ => None,
+        }
+    }
+}
+
+/// A `-D` or `-U` option, applied in the order given.
+#[derive(Clone)]
+enum MacroOption {
+    Define(String),
+    Undefine(String),
+}
+
+#[derive(Clone)]
+struct Options {
+    help: bool,
+    format: Format,
+    /// The top-level invocation drawn by the graph formats, counting from 1.
+    invocation: usize,
+    /// Whether to explain inputs interactively instead of reading whole files.
+    repl: bool,
+    /// Whether to step through the input, with debugger commands read from the standard
+    /// input.
+    debug: bool,
+    /// The files to preprocess, each as a separate translation unit. `-` is the standard
+    /// input.
+    inputs: Vec<String>,
+    output: Option<String>,
+    macros: Vec<MacroOption>,
+    include_dirs: Vec<String>,
+    system_include_dirs: Vec<String>,
+    /// Whether the text format prints the explanation.
+    prints_trace: bool,
+    /// Whether the text format prints the preprocessed code.
+    prints_code: bool,
+    /// The compiler whose preprocessor the output is compared with.
+    check_against: Option<String>,
+    /// Whether to reduce the input to a smaller one whose output still differs from the
+    /// compiler.
+    minimize: bool,
+    /// The options of cc that do not matter to the preprocessor and are ignored.
+    ignored: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        help: false,
+        format: Format::Text,
+        invocation: 1,
+        repl: false,
+        debug: false,
+        inputs: Vec::new(),
+        output: None,
+        macros: Vec::new(),
+        include_dirs: Vec::new(),
+        system_include_dirs: Vec::new(),
+        prints_trace: true,
+        prints_code: true,
+        check_against: None,
+        minimize: false,
+        ignored: Vec::new(),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // As in cc, the value of a short option is either attached or the next argument.
+        if let Some(flag) = ["-D", "-U", "-I", "-isystem", "-o"]
+            .into_iter()
+            .find(|f| arg.starts_with(f))
+        {
+            let value = match &arg[flag.len()..] {
+                "" => args.next().ok_or(format!("{} expects a value", flag))?,
+                value => value.to_string(),
+            };
+            match flag {
+                "-D" => options.macros.push(MacroOption::Define(value)),
+                "-U" => options.macros.push(MacroOption::Undefine(value)),
+                "-I" => options.include_dirs.push(value),
+                "-isystem" => options.system_include_dirs.push(value),
+                _ => options.output = Some(value),
+            }
+            continue;
+        }
+        if arg == "-" || !arg.starts_with('-') {
+            options.inputs.push(arg);
+            continue;
+        }
+        let spelling = arg.clone();
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or(format!("{} expects a value", name))
+        };
+        match name.as_str() {
+            "--format" => {
+                let value = value()?;
+                options.format =
+                    Format::parse(&value).ok_or_else(|| format!("unknown format `{}`", value))?;
+            }
+            "--invocation" => {
+                let value = value()?;
+                options.invocation = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid invocation number `{}`", value))?;
+            }
+            "--help" => options.help = true,
+            "--check-against" => options.check_against = Some(value()?),
+            "--minimize" => options.minimize = true,
+            "--repl" => options.repl = true,
+            "--debug" => options.debug = true,
+            "--trace" => options.prints_trace = true,
+            "--no-trace" => options.prints_trace = false,
+            "--explain-only" => (options.prints_trace, options.prints_code) = (true, false),
+            "--output-only" => (options.prints_trace, options.prints_code) = (false, true),
+            // Other options of cc, such as -std=c11 or -Wall, are accepted so that compile
+            // flags can be reused as they are.
+            _ if IGNORED_WITH_VALUE.contains(&name.as_str()) => {
+                options.ignored.push(format!("{} {}", name, value()?))
+            }
+            _ => options.ignored.push(spelling),
+        }
+    }
+    if options.inputs.is_empty() {
+        options.inputs.push("-".to_string());
+    }
+    if options.inputs.len() > 1 && !matches!(options.format, Format::Text | Format::Json) {
+        return Err("only the text and json formats take several inputs".to_string());
+    }
+    if options.debug && (options.inputs.len() > 1 || options.inputs[0] == "-") {
+        return Err(
+            "--debug takes one input file, as commands are read from the standard input"
+                .to_string(),
+        );
+    }
+    if options.minimize && options.inputs.len() > 1 {
+        return Err("--minimize takes one input".to_string());
+    }
+    Ok(options)
+}
+
+/// Creates a preprocessor with the macros and include directories given as options.
+fn new_preprocessor(options: &Options) -> MacroPreprocessor {
+    let mut preprocessor = MacroPreprocessor::new();
+    for dir in &options.include_dirs {
+        preprocessor.add_user_include_dir(dir);
+    }
+    for dir in &options.system_include_dirs {
+        preprocessor.add_system_include_dir(dir);
+    }
+    for option in &options.macros {
+        match option {
+            MacroOption::Define(definition) => preprocessor.define(definition),
+            MacroOption::Undefine(name) => preprocessor.undefine(name),
+        }
+    }
+    preprocessor
+}
+
+/// Whether the output needs the steps of the preprocessing: the explanation, the JSON and
+/// the graphs do, but neither the preprocessed code alone, nor the rewrite view, nor the
+/// comparison with a compiler, which preprocesses the inputs that differ again to explain
+/// them.
+fn records_steps(options: &Options) -> bool {
+    let explains = match options.format {
+        Format::Text => options.prints_trace,
+        Format::Rewrite => false,
+        _ => true,
+    };
+    explains && options.check_against.is_none() && !options.minimize
+}
+
+/// Whether the output is the rewrite view, the only one that needs the rewrites.
+fn records_rewrites(options: &Options) -> bool {
+    options.format == Format::Rewrite && options.check_against.is_none() && !options.minimize
+}
+
+/// An input together with its preprocessing.
+struct Unit {
+    /// The file, or `None` for the standard input.
+    file: Option<String>,
+    source: String,
+    result: Preprocessed,
+}
+
+fn preprocess(options: &Options, input: &str) -> Result<Unit, String> {
+    let mut preprocessor = new_preprocessor(options);
+    preprocessor.set_recording(records_steps(options));
+    preprocessor.set_recording_rewrites(records_rewrites(options));
+    if input == "-" {
+        let mut source = String::new();
+        std::io::stdin()
+            .read_to_string(&mut source)
+            .map_err(|err| format!("cannot read the standard input: {}", err))?;
+        let result = preprocessor.preprocess(&source);
+        return Ok(Unit {
+            file: None,
+            source,
+            result,
+        });
+    }
+    let source =
+        std::fs::read_to_string(input).map_err(|err| format!("cannot read {}: {}", input, err))?;
+    let result = preprocessor
+        .preprocess_file(input)
+        .ok_or_else(|| format!("cannot read {}", input))?;
+    Ok(Unit {
+        file: Some(input.to_string()),
+        source,
+        result,
+    })
+}
+
+/// Formats each step of the trace indented under the step it is part of.
+pub(crate) fn format_trace(nodes: &[Node], depth: usize) -> String {
+    let mut text = String::new();
+    for node in nodes {
+        text += &format!("{}{}\n", "  ".repeat(depth), node.event);
+        text += &format_trace(&node.children, depth + 1);
+    }
+    text
+}
+
+fn format_text(options: &Options, result: &Preprocessed) -> String {
+    let mut text = String::new();
+    if options.prints_trace {
+        text += &format_trace(&result.trace, 0);
+    }
+    match (options.prints_trace, options.prints_code) {
+        (true, true) => text += &format!("Preprocessed code:\n{}\n", result.output),
+        (false, true) => text += &result.output,
+        _ => {}
+    }
+    text
+}
+
+/// Formats the expansion graph of the `n`th top-level invocation with `render`.
+fn format_graph(
+    result: &Preprocessed,
+    n: usize,
+    render: fn(&Node) -> String,
+) -> Result<String, String> {
+    let invocations = c_macro_explainer::invocations(&result.trace);
+    match invocations.get(n - 1) {
+        Some(invocation) => Ok(render(invocation)),
+        None => Err(format!(
+            "invocation {} is requested, but the input has only {} top-level invocations",
+            n,
+            invocations.len()
+        )),
+    }
+}
+
+fn format(options: &Options, units: &[Unit]) -> Result<String, String> {
+    let unit = &units[0];
+    let result = &unit.result;
+    Ok(match options.format {
+        Format::Text => units
+            .iter()
+            .map(|unit| format_text(options, &unit.result))
+            .collect(),
+        Format::Json => {
+            let results: Vec<&Preprocessed> = units.iter().map(|unit| &unit.result).collect();
+            serde_json::to_string_pretty(&results).unwrap() + "\n"
+        }
+        Format::Dot => format_graph(result, options.invocation, c_macro_explainer::to_dot)?,
+        Format::Mermaid => format_graph(result, options.invocation, c_macro_explainer::to_mermaid)?,
+        Format::Html => {
+            let file = unit.file.as_deref().map(Path::new);
+            c_macro_explainer::to_html(&unit.source, file, result)
+        }
+        Format::Rewrite => c_macro_explainer::rewrite_view(&result.rewrites),
+    })
+}
+
+/// Preprocesses a unit again with `compiler -E -P`, given the same macros and include
+/// directories.
+fn run_compiler(options: &Options, compiler: &str, unit: &Unit) -> Result<String, String> {
+    let mut command = Command::new(compiler);
+    for option in &options.macros {
+        match option {
+            MacroOption::Define(definition) => command.arg(format!("-D{}", definition)),
+            MacroOption::Undefine(name) => command.arg(format!("-U{}", name)),
+        };
+    }
+    for dir in &options.include_dirs {
+        command.arg(format!("-I{}", dir));
+    }
+    for dir in &options.system_include_dirs {
+        command.arg("-isystem").arg(dir);
+    }
+    command.arg("-E").arg("-P");
+    command.arg(unit.file.as_deref().unwrap_or("-"));
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("cannot run {}: {}", compiler, err))?;
+    let mut stdin = child.stdin.take().unwrap();
+    if unit.file.is_none() {
+        // A compiler failing early may not read it all, which its status reports below.
+        let _ = stdin.write_all(unit.source.as_bytes());
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed to preprocess {}:\n{}",
+            compiler,
+            unit.file.as_deref().unwrap_or("the standard input"),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|err| format!("{} printed {}", compiler, err))
+}
+
+/// Preprocesses a unit again, recording the steps that were not recorded the first time.
+fn explain(options: &Options, unit: &Unit) -> Result<Preprocessed, String> {
+    let mut preprocessor = new_preprocessor(options);
+    match &unit.file {
+        Some(file) => preprocessor
+            .preprocess_file(file)
+            .ok_or_else(|| format!("cannot read {}", file)),
+        None => Ok(preprocessor.preprocess(&unit.source)),
+    }
+}
+
+/// Reports the lines where the output of each unit differs from `compiler`, with the
+/// explanation of the invocations on them. Returns the report and the number of
+/// differences.
+fn check(options: &Options, compiler: &str, units: &[Unit]) -> Result<(String, usize), String> {
+    let mut text = String::new();
+    let mut count = 0;
+    for unit in units {
+        let reference = run_compiler(options, compiler, unit)?;
+        let mut mismatches = c_macro_explainer::compare(&unit.result, &reference);
+        if !mismatches.is_empty() {
+            mismatches = c_macro_explainer::compare(&explain(options, unit)?, &reference);
+        }
+        for mismatch in mismatches {
+            count += 1;
+            match &mismatch.span {
+                Some(span) => text += &format!("{}: the output differs from {}\n", span, compiler),
+                None => text += &format!("the end of the output differs from {}\n", compiler),
+            }
+            for line in &mismatch.actual {
+                text += &format!("  explainer: {}\n", line);
+            }
+            for line in &mismatch.expected {
+                text += &format!("  {:10} {}\n", format!("{}:", compiler), line);
+            }
+            text += &format_trace(&mismatch.trace, 1);
+        }
+    }
+    if count == 0 {
+        text += &format!("The output is the same as {}.\n", compiler);
+    }
+    Ok((text, count))
+}
+
+/// Reduces a unit whose output differs from `compiler` to a smaller source that still
+/// differs.
+fn minimize(options: &Options, compiler: &str, unit: &Unit) -> Result<String, String> {
+    // The candidates are given on the standard input, so includes are searched for in the
+    // directory of the input as well.
+    let mut options = options.clone();
+    let dir = unit
+        .file
+        .as_deref()
+        .and_then(|file| Path::new(file).parent());
+    if let Some(dir) = dir.filter(|dir| !dir.as_os_str().is_empty()) {
+        options
+            .include_dirs
+            .push(dir.to_string_lossy().into_owned());
+    }
+    let differs = |source: &str| {
+        let mut preprocessor = new_preprocessor(&options);
+        preprocessor.set_recording(false);
+        let candidate = Unit {
+            file: None,
+            source: source.to_string(),
+            result: preprocessor.preprocess(source),
+        };
+        run_compiler(&options, compiler, &candidate).is_ok_and(|reference| {
+            !c_macro_explainer::compare(&candidate.result, &reference).is_empty()
+        })
+    };
+    if !differs(&unit.source) {
+        return Err(format!(
+            "the output is the same as {}, or {} fails on the input",
+            compiler, compiler
+        ));
+    }
+    let reduced = c_macro_explainer::minimize(&unit.source, differs);
+    eprintln!(
+        "Reduced {} lines to {}.",
+        unit.source.lines().count(),
+        reduced.lines().count()
+    );
+    Ok(reduced)
+}
+
+/// Prints the diagnostics, and returns whether any of them is an error.
+fn report<'a>(diagnostics: impl IntoIterator<Item = &'a Diagnostic>) -> bool {
+    let mut has_errors = false;
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic);
+        has_errors |= diagnostic.severity == Severity::Error;
+    }
+    has_errors
+}
+
+/// Runs the command, and returns whether it succeeded without reporting errors.
+fn run(options: &Options) -> Result<bool, String> {
+    if options.help {
+        println!("{}", USAGE);
+        return Ok(true);
+    }
+    if options.repl {
+        repl::run(|| new_preprocessor(options));
+        return Ok(true);
+    }
+    if options.debug {
+        let mut preprocessor = new_preprocessor(options);
+        preprocessor.set_debugger(debugger::Console::new());
+        let path = &options.inputs[0];
+        let output = preprocessor
+            .process_file(path)
+            .ok_or_else(|| format!("cannot read {}", path))?;
+        let has_errors = report(&preprocessor.take_diagnostics());
+        println!("Preprocessed code:\n{}", output);
+        return Ok(!has_errors);
+    }
+    let units = options
+        .inputs
+        .iter()
+        .map(|input| preprocess(options, input))
+        .collect::<Result<Vec<_>, _>>()?;
+    let has_errors = report(units.iter().flat_map(|unit| &unit.result.diagnostics));
+    let (text, mismatches) = match &options.check_against {
+        _ if options.minimize => {
+            let compiler = options.check_against.as_deref().unwrap_or("cc");
+            (minimize(options, compiler, &units[0])?, 0)
+        }
+        Some(compiler) => check(options, compiler, &units)?,
+        None => (format(options, &units)?, 0),
+    };
+    match &options.output {
+        Some(path) => {
+            std::fs::write(path, text).map_err(|err| format!("cannot write {}: {}", path, err))?
+        }
+        None => std::io::stdout().write_all(text.as_bytes()).unwrap(),
+    }
+    match mismatches {
+        0 => Ok(!has_errors),
+        1 => Err("1 difference is found".to_string()),
+        count => Err(format!("{} differences are found", count)),
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    for option in &options.ignored {
+        eprintln!("warning: ignoring the option `{}`", option);
+    }
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_command_line() {
+        let args = "-std=c11 -Wall -Wextra -O2 -g -fPIC -DNDEBUG -D VERSION=2 -UDEBUG -Iinclude \
+                    -I src -isystem /opt/include -isystem/usr/local/include -x c -MMD -MF main.d \
+                    -c main.c -o main.i";
+        let options = parse_args(args.split(' ').map(String::from)).unwrap();
+        assert_eq!(options.inputs, ["main.c"]);
+        assert_eq!(options.output.as_deref(), Some("main.i"));
+        assert_eq!(options.include_dirs, ["include", "src"]);
+        assert_eq!(
+            options.system_include_dirs,
+            ["/opt/include", "/usr/local/include"]
+        );
+        let macros: Vec<String> = options
+            .macros
+            .iter()
+            .map(|option| match option {
+                MacroOption::Define(definition) => format!("-D{}", definition),
+                MacroOption::Undefine(name) => format!("-U{}", name),
+            })
+            .collect();
+        assert_eq!(macros, ["-DNDEBUG", "-DVERSION=2", "-UDEBUG"]);
+        assert_eq!(
+            options.ignored,
+            [
+                "-std=c11",
+                "-Wall",
+                "-Wextra",
+                "-O2",
+                "-g",
+                "-fPIC",
+                "-x c",
+                "-MMD",
+                "-MF main.d",
+                "-c",
+            ]
+        );
+    }
 }
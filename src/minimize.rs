@@ -0,0 +1,144 @@
+use crate::lexer::{self, PpToken, Token};
+
+/// Reduces `source` by delta debugging while `is_interesting` still holds for it, such as
+/// while the explainer and the compiler still disagree on it. Lines, such as `#define`s,
+/// are removed first, then invocations, arguments and single tokens inside the lines, until
+/// nothing more can be removed. `is_interesting` must hold for `source`.
+pub fn minimize(source: &str, mut is_interesting: impl FnMut(&str) -> bool) -> String {
+    let mut lines = logical_lines(source);
+    loop {
+        let count = lines.len();
+        let length: usize = lines.iter().map(String::len).sum();
+        lines = remove_lines(lines, &mut is_interesting);
+        for i in 0..lines.len() {
+            while let Some(line) = remove_tokens(&lines, i, &mut is_interesting) {
+                lines[i] = line;
+            }
+        }
+        if lines.len() == count && lines.iter().map(String::len).sum::<usize>() == length {
+            return join(&lines);
+        }
+    }
+}
+
+/// Splits source code into lines, keeping the lines continued by a backslash together.
+fn logical_lines(source: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut is_continued = false;
+    for line in source.lines() {
+        match lines.last_mut() {
+            Some(last) if is_continued => {
+                last.push('\n');
+                last.push_str(line);
+            }
+            _ => lines.push(line.to_string()),
+        }
+        is_continued = line.ends_with('\\');
+    }
+    lines
+}
+
+fn join(lines: &[String]) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Removes as many lines as possible with the ddmin algorithm: the lines are split into
+/// chunks, and a chunk is removed if the rest is still interesting. The chunks are made
+/// smaller when none can be removed.
+fn remove_lines(
+    mut lines: Vec<String>,
+    is_interesting: &mut impl FnMut(&str) -> bool,
+) -> Vec<String> {
+    let mut chunks = 2;
+    while lines.len() >= 2 {
+        let size = lines.len().div_ceil(chunks);
+        let removed = (0..lines.len()).step_by(size).find_map(|start| {
+            let mut rest = lines.clone();
+            rest.drain(start..(start + size).min(lines.len()));
+            is_interesting(&join(&rest)).then_some(rest)
+        });
+        match removed {
+            Some(rest) => {
+                lines = rest;
+                chunks = (chunks - 1).max(2);
+            }
+            None if size == 1 => break,
+            None => chunks = (chunks * 2).min(lines.len()),
+        }
+    }
+    lines
+}
+
+/// Returns the line at `index` with an invocation, an argument or a token removed, if the
+/// result is still interesting. Larger parts are tried first.
+fn remove_tokens(
+    lines: &[String],
+    index: usize,
+    is_interesting: &mut impl FnMut(&str) -> bool,
+) -> Option<String> {
+    let line = &lines[index];
+    // The columns of the tokens are byte offsets only if no line is spliced.
+    if line.contains("\\\n") {
+        return None;
+    }
+    let tokens = lexer::tokenize(line, None);
+    let start = |token: &PpToken| token.column - 1;
+    let end = |token: &PpToken| token.column - 1 + token.text.len();
+    let mut lines = lines.to_vec();
+    removable_ranges(&tokens)
+        .into_iter()
+        .find_map(|(first, last)| {
+            let reduced = format!(
+                "{}{}",
+                &line[..start(&tokens[first])],
+                &line[end(&tokens[last])..]
+            );
+            lines[index] = reduced;
+            is_interesting(&join(&lines)).then(|| lines[index].clone())
+        })
+}
+
+/// Returns the ranges of tokens, first and last included, that may be removed from a line:
+/// the invocations, the arguments with their comma, the contents of parentheses, and every
+/// token, from the longest.
+fn removable_ranges(tokens: &[PpToken]) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut open = Vec::new();
+    // The commas of each parenthesis being open.
+    let mut commas: Vec<Vec<usize>> = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            Token::LParen => {
+                open.push(i);
+                commas.push(Vec::new());
+            }
+            Token::Comma if !open.is_empty() => commas.last_mut().unwrap().push(i),
+            Token::RParen if !open.is_empty() => {
+                let lparen = open.pop().unwrap();
+                let commas = commas.pop().unwrap();
+                if lparen > 0 && tokens[lparen - 1].kind == Token::Identifier {
+                    ranges.push((lparen - 1, i));
+                }
+                if i > lparen + 1 {
+                    ranges.push((lparen + 1, i - 1));
+                }
+                // Each argument goes with the comma before it, or after it for the first one.
+                let mut bounds = vec![lparen];
+                bounds.extend(&commas);
+                bounds.push(i);
+                for (k, pair) in bounds.windows(2).enumerate() {
+                    match k {
+                        0 if commas.is_empty() => {}
+                        0 => ranges.push((pair[0] + 1, pair[1])),
+                        _ => ranges.push((pair[0], pair[1] - 1)),
+                    }
+                }
+            }
+            _ => {}
+        }
+        ranges.push((i, i));
+    }
+    ranges.sort_by_key(|&(first, last)| (first as isize - last as isize, first));
+    ranges.dedup();
+    ranges
+}
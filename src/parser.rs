@@ -1,144 +1,272 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::{alpha1, alphanumeric1, multispace0, multispace1},
-    combinator::{opt, recognize, value},
-    multi::{many0_count, separated_list0},
-    sequence::{delimited, pair, preceded, terminated, tuple},
+    bytes::complete::{tag, take_till1},
+    sequence::delimited,
     IResult, Parser,
 };
 
-pub fn identifier(input: &str) -> IResult<&str, &str> {
-    recognize(pair(
-        alt((alpha1, tag("_"))),
-        many0_count(alt((alphanumeric1, tag("_")))),
-    ))
-    .parse(input)
-}
+use crate::diagnostic::{Diagnostic, Span};
+use crate::include::HeaderKind;
+use crate::lexer::{PpToken, Token};
 
-pub fn parse_define<'a>(input: &'a str) -> IResult<&'a str, (&'a str, Macro)> {
-    let define_directive = tuple((tag("#"), multispace0, tag("define"), multispace1));
-    let parameter_list = delimited(
-        tag("("),
-        separated_list0(
-            tag(","),
-            delimited(multispace0, alt((tag("..."), identifier)), multispace0),
-        ),
-        tag(")"),
-    );
-    let body = |input: &'a str| {
-        let mut is_escaped = false;
-        let mut is_new_line = false;
-        let mut eatten = String::new();
-        for (i, ch) in input.char_indices() {
-            if ch == '\\' {
-                is_escaped = !is_escaped;
-            } else if is_escaped {
-                is_escaped = false;
-                if ch == '\n' {
-                    is_new_line = true;
-                } else {
-                    eatten.push('\\');
-                    eatten.push(ch);
-                }
-            } else if is_new_line && ch == ' ' {
-                continue;
-            } else if ch == '\n' && !is_escaped {
-                return IResult::Ok((&input[i..], eatten.trim().to_string()));
-            } else {
-                is_new_line = false;
-                eatten.push(ch);
-            }
+/// Parses a `#define` line: the macro name, the parameter list if the macro is
+/// function-like, and the body. Warnings are added to `diagnostics`.
+pub fn parse_define<'a>(
+    line: &'a [PpToken],
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<(&'a str, Macro), Diagnostic> {
+    // Errors about missing tokens point to the end of the line.
+    let end = line.last().unwrap().span();
+    let (name, span, mut rest) = match line.get(2..).and_then(<[PpToken]>::split_first) {
+        Some((name, rest)) if name.kind == Token::Identifier => (&*name.text, name.span(), rest),
+        Some((token, _)) => {
+            return Err(Diagnostic::error(
+                "macro names must be identifiers",
+                token.span(),
+            ))
+        }
+        None => {
+            return Err(Diagnostic::error(
+                "no macro name given in #define directive",
+                end,
+            ))
         }
-        IResult::Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Eof,
-        )))
     };
-    preceded(
-        define_directive,
-        tuple((
-            terminated(identifier, multispace0),
-            opt(parameter_list),
-            body,
-        )),
-    )
-    .map(|(name, params, body): (&str, Option<Vec<&str>>, String)| {
-        if let Some(params) = params {
-            (
+    // A function-like macro has a `(` right after its name, without whitespace in between.
+    let params = match rest.first() {
+        Some(token) if token.kind == Token::LParen && !token.has_space => {
+            let (params, is_variadic, after) = parse_parameter_list(&rest[1..], &end)?;
+            rest = after;
+            Some((params, is_variadic))
+        }
+        _ => None,
+    };
+
+    let mut body = rest.to_vec();
+    for token in &mut body {
+        token.is_line_start = false;
+    }
+    if let Some(first) = body.first_mut() {
+        first.has_space = false;
+    }
+    for token in [body.first(), body.last()].into_iter().flatten() {
+        if token.kind == Token::HashHash {
+            return Err(Diagnostic::error(
+                "'##' cannot appear at either end of a macro expansion",
+                token.span(),
+            ));
+        }
+    }
+    let has_va_args = params
+        .as_ref()
+        .is_some_and(|(params, _)| params.iter().any(|p| p == "__VA_ARGS__"));
+    for token in &body {
+        if token.is_identifier("__VA_ARGS__") && !has_va_args {
+            diagnostics.push(Diagnostic::warning(
+                "__VA_ARGS__ can only appear in the expansion of a C99 variadic macro",
+                token.span(),
+            ));
+        }
+    }
+
+    match params {
+        Some((params, is_variadic)) => {
+            let is_param = |t: &PpToken| {
+                t.kind == Token::Identifier
+                    && (params.iter().any(|p| *p == *t.text)
+                        || (is_variadic && &*t.text == "__VA_OPT__"))
+            };
+            let mut va_opt_end = None;
+            for (i, token) in body.iter().enumerate() {
+                if token.kind == Token::Hash && !body.get(i + 1).is_some_and(is_param) {
+                    return Err(Diagnostic::error(
+                        "'#' is not followed by a macro parameter",
+                        token.span(),
+                    ));
+                }
+                if !token.is_identifier("__VA_OPT__") {
+                    continue;
+                }
+                let error = |message: &str| Err(Diagnostic::error(message, token.span()));
+                if !is_variadic {
+                    return error(
+                        "__VA_OPT__ can only appear in the expansion of a variadic macro",
+                    );
+                }
+                if va_opt_end.is_some_and(|end| i < end) {
+                    return error("__VA_OPT__ may not appear in a __VA_OPT__");
+                }
+                let Some(end) = find_va_opt_end(&body, i) else {
+                    return error("unterminated __VA_OPT__");
+                };
+                let content = &body[i + 2..end];
+                if content.first().is_some_and(|t| t.kind == Token::HashHash)
+                    || content.last().is_some_and(|t| t.kind == Token::HashHash)
+                {
+                    return error("'##' cannot appear at either end of __VA_OPT__");
+                }
+                va_opt_end = Some(end);
+            }
+            Ok((
                 name,
                 Macro::Function {
-                    params: params.into_iter().map(String::from).collect(),
+                    params,
+                    is_variadic,
                     body,
+                    span,
                 },
-            )
-        } else {
-            (name, Macro::Object { body })
+            ))
         }
-    })
-    .parse(input)
+        None => Ok((name, Macro::Object { body, span })),
+    }
 }
 
-pub fn parse_undef(input: &str) -> IResult<&str, &str> {
-    preceded(
-        tuple((tag("#"), multispace0, tag("undef"), multispace1)),
-        identifier,
-    )
-    .parse(input)
+/// Finds the `)` closing `__VA_OPT__ (` whose `__VA_OPT__` is at `start` in a macro body.
+pub fn find_va_opt_end(body: &[PpToken], start: usize) -> Option<usize> {
+    if !body.get(start + 1).is_some_and(|t| t.kind == Token::LParen) {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, token) in body.iter().enumerate().skip(start + 1) {
+        match token.kind {
+            Token::LParen => depth += 1,
+            Token::RParen if depth == 1 => return Some(i),
+            Token::RParen => depth -= 1,
+            _ => {}
+        }
+    }
+    None
 }
 
-pub fn parse_stringify(input: &str) -> IResult<&str, &str> {
-    preceded(tuple((tag("#"), multispace0)), identifier).parse(input)
+/// Parses the parameters after the `(` of a function-like macro, up to and including `)`,
+/// and whether the macro is variadic. The variadic parameter is named `__VA_ARGS__` for
+/// `...`, or by its identifier for the GNU form `args...`.
+fn parse_parameter_list<'a>(
+    tokens: &'a [PpToken],
+    end: &Span,
+) -> Result<(Vec<String>, bool, &'a [PpToken]), Diagnostic> {
+    let error = |message: &str, token: Option<&PpToken>| {
+        Err(Diagnostic::error(
+            message,
+            token.map_or_else(|| end.clone(), PpToken::span),
+        ))
+    };
+    let mut params: Vec<String> = Vec::new();
+    let mut rest = tokens;
+    if let Some((token, after)) = rest.split_first() {
+        if token.kind == Token::RParen {
+            return Ok((params, false, after));
+        }
+    }
+    loop {
+        let is_variadic = match rest {
+            [token, after @ ..] if token.kind == Token::Ellipsis => {
+                params.push("__VA_ARGS__".to_string());
+                rest = after;
+                true
+            }
+            [token, after @ ..] if token.kind == Token::Identifier => {
+                if &*token.text == "__VA_ARGS__" {
+                    return error(
+                        "__VA_ARGS__ can not be used as a parameter name",
+                        Some(token),
+                    );
+                }
+                if params.iter().any(|param| *param == *token.text) {
+                    return error(
+                        &format!("duplicate macro parameter \"{}\"", token.text),
+                        Some(token),
+                    );
+                }
+                params.push(token.text.to_string());
+                rest = after;
+                // The GNU form `args...` names the variable arguments.
+                match rest.split_first() {
+                    Some((ellipsis, after)) if ellipsis.kind == Token::Ellipsis => {
+                        rest = after;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => return error("expected parameter name", rest.first()),
+        };
+        match rest.split_first() {
+            Some((token, after)) if token.kind == Token::RParen => {
+                return Ok((params, is_variadic, after))
+            }
+            token if is_variadic => return error("missing ')' after \"...\"", token.map(|t| t.0)),
+            Some((token, after)) if token.kind == Token::Comma => rest = after,
+            token => {
+                return error(
+                    "expected ',' or ')' in macro parameter list",
+                    token.map(|t| t.0),
+                )
+            }
+        }
+    }
 }
 
-pub fn parse_concatenation(input: &str) -> IResult<&str, (&str, &str)> {
-    tuple((
-        identifier,
-        delimited(multispace0, tag("##"), multispace0),
-        identifier,
-    ))
-    .map(|(lhs, _, rhs)| (lhs, rhs))
-    .parse(input)
+/// Parses the macro name expected by `#undef`, `#ifdef` and `#ifndef`, returning it with
+/// the extra tokens after it, which GCC ignores with a warning.
+pub fn parse_directive_identifier(tokens: &[PpToken]) -> Option<(&str, &[PpToken])> {
+    match tokens {
+        [name, extra @ ..] if name.kind == Token::Identifier => Some((&name.text, extra)),
+        _ => None,
+    }
+}
+
+/// Parses the `defined X` or `defined ( X )` operator of `#if`, returning the macro name
+/// and the number of tokens making up the operator.
+pub fn parse_defined(tokens: &[PpToken]) -> Option<(&str, usize)> {
+    if !tokens.first()?.is_identifier("defined") {
+        return None;
+    }
+    match &tokens[1..] {
+        [name, ..] if name.kind == Token::Identifier => Some((&name.text, 2)),
+        [lparen, name, rparen, ..]
+            if lparen.kind == Token::LParen
+                && name.kind == Token::Identifier
+                && rparen.kind == Token::RParen =>
+        {
+            Some((&name.text, 4))
+        }
+        _ => None,
+    }
 }
 
-pub fn parse_comment(input: &str) -> IResult<&str, ()> {
+/// Parses the operand of `#include`, either `"name"` or `<name>`.
+pub fn parse_header_name(input: &str) -> IResult<&str, (&str, HeaderKind)> {
     alt((
-        value((), tuple((tag("//"), take_until("\n")))),
-        value((), tuple((tag("/*"), take_until("*/")))),
+        delimited(tag("\""), take_till1(|c| c == '"'), tag("\""))
+            .map(|name| (name, HeaderKind::Quoted)),
+        delimited(tag("<"), take_till1(|c| c == '>'), tag(">"))
+            .map(|name| (name, HeaderKind::Angled)),
     ))
     .parse(input)
 }
 
-pub fn parse_macro_call<'a>(input: &'a str) -> IResult<&'a str, (&'a str, Vec<&'a str>)> {
-    let argument = |input: &'a str| -> IResult<&'a str, &'a str> {
-        let mut paren_depth = 0;
-        for (end, ch) in input.char_indices() {
-            if (ch == ',' || ch == ')') && paren_depth == 0 {
-                return IResult::Ok((&input[end..], &input[..end].trim()));
-            }
-            if ch == '(' {
-                paren_depth += 1;
-            } else if ch == ')' {
-                paren_depth -= 1;
-            }
-        }
-        IResult::Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Eof,
-        )))
-    };
-    let argument_list = delimited(
-        tag("("),
-        separated_list0(tag(","), delimited(multispace0, argument, multispace0)),
-        tag(")"),
-    );
-    tuple((identifier, preceded(multispace0, argument_list)))
-        .map(|(name, params)| (name, params.into_iter().collect()))
-        .parse(input)
-}
-
+/// A macro definition. `span` is where the macro name is in its `#define`.
 #[derive(Debug, Clone)]
 pub enum Macro {
-    Object { body: String },
-    Function { params: Vec<String>, body: String },
+    Object {
+        body: Vec<PpToken>,
+        span: Span,
+    },
+    /// A function-like macro. If it is variadic, the last parameter receives the variable
+    /// arguments.
+    Function {
+        params: Vec<String>,
+        is_variadic: bool,
+        body: Vec<PpToken>,
+        span: Span,
+    },
+}
+
+impl Macro {
+    pub fn span(&self) -> &Span {
+        match self {
+            Macro::Object { span, .. } | Macro::Function { span, .. } => span,
+        }
+    }
 }
@@ -1,24 +1,216 @@
 use itertools::Itertools;
-use nom::Parser;
+use serde::Serialize;
 use std::{
-    ascii::escape_default,
-    collections::{HashMap, HashSet},
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    path::{Path, PathBuf},
 };
 
+use crate::debug::{Debugger, Replacement};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expr::{self, Value};
+use crate::include::{FileProvider, FileSystem, HeaderKind, IncludePaths};
+use crate::lexer::{self, HideSet, PpToken, Token};
 use crate::parser::{
-    identifier, parse_comment, parse_concatenation, parse_define, parse_macro_call,
-    parse_stringify, parse_undef, Macro,
+    find_va_opt_end, parse_define, parse_defined, parse_directive_identifier, parse_header_name,
+    Macro,
 };
+use crate::rewrite::Rewrite;
+use crate::trace::{Binding, Event, Node, Site, TraceBuilder};
 
-#[derive(Debug, Default)]
-struct MacroPreprocessor {
+/// The file name given to macros defined by `define` and `undefine`, as in GCC.
+const COMMAND_LINE: &str = "<command-line>";
+
+/// Nested `#include`s deeper than this are assumed to be recursive, as in GCC.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+pub struct MacroPreprocessor {
     macros: HashMap<String, Macro>,
+    include_paths: IncludePaths,
+    file_provider: Box<dyn FileProvider>,
+    /// Files containing `#pragma once`, which are not included again.
+    once_files: HashSet<PathBuf>,
+    include_depth: usize,
+    /// Problems found so far, collected while expanding through `&self`.
+    diagnostics: RefCell<Vec<Diagnostic>>,
+    /// The steps taken so far, collected like `diagnostics`.
+    trace: RefCell<TraceBuilder>,
+    /// The invocations replaced so far, shown in the line being scanned.
+    rewrites: RefCell<Vec<Rewrite>>,
+    /// The line left by the last rewrite, which the next one continues if it is the same.
+    rewritten_line: RefCell<String>,
+    /// The tokens shown before and after an argument in the rewrite view while it is
+    /// pre-expanded, from the outermost argument.
+    rewrite_contexts: RefCell<Vec<(Vec<PpToken>, Vec<PpToken>)>>,
+    debugger: RefCell<Option<Box<dyn Debugger>>>,
+    /// Whether the steps are recorded, which only the explanations need.
+    is_recording: bool,
+    /// Whether the rewrites are recorded, which only the rewrite view needs.
+    is_recording_rewrites: bool,
+    /// The `#if` or `#elif` whose condition is being macro-expanded, where a `defined`
+    /// produced by a macro is evaluated as in GCC.
+    condition: RefCell<Option<Span>>,
+    /// The invocation written in the source whose expansion is being scanned, where problems
+    /// with the invocations in macro bodies are reported.
+    expansion_site: RefCell<Option<Span>>,
+    /// The groups of conditionals kept so far, recorded with the steps.
+    kept_groups: Vec<KeptGroup>,
+}
+
+impl Default for MacroPreprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MacroPreprocessor {
-    fn new() -> Self {
+    /// Creates a preprocessor that reads included files from the file system.
+    pub fn new() -> Self {
+        Self::with_file_provider(FileSystem)
+    }
+
+    /// Creates a preprocessor that reads included files from `file_provider`.
+    pub fn with_file_provider(file_provider: impl FileProvider + 'static) -> Self {
         Self {
             macros: HashMap::new(),
+            include_paths: IncludePaths::default(),
+            file_provider: Box::new(file_provider),
+            once_files: HashSet::new(),
+            include_depth: 0,
+            diagnostics: RefCell::new(Vec::new()),
+            trace: RefCell::new(TraceBuilder::default()),
+            rewrites: RefCell::new(Vec::new()),
+            rewritten_line: RefCell::new(String::new()),
+            rewrite_contexts: RefCell::new(Vec::new()),
+            debugger: RefCell::new(None),
+            is_recording: true,
+            is_recording_rewrites: false,
+            condition: RefCell::new(None),
+            expansion_site: RefCell::new(None),
+            kept_groups: Vec::new(),
+        }
+    }
+
+    /// Adds a directory searched by both `#include "..."` and `#include <...>`, like `-I`.
+    pub fn add_user_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_paths.user.push(dir.into());
+    }
+
+    /// Adds a directory searched after every user directory, like `-isystem`.
+    pub fn add_system_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_paths.system.push(dir.into());
+    }
+
+    /// Pauses the expansion with `debugger` before each macro replacement.
+    pub fn set_debugger(&mut self, debugger: impl Debugger + 'static) {
+        *self.debugger.get_mut() = Some(Box::new(debugger));
+    }
+
+    /// Sets whether the steps are recorded for `take_trace`, which they are by default.
+    /// Without them, only the output and the diagnostics are produced, faster. They are
+    /// still recorded for a debugger.
+    pub fn set_recording(&mut self, is_recording: bool) {
+        self.is_recording = is_recording;
+    }
+
+    /// Sets whether the rewrites are recorded for `take_rewrites`, which they are not by
+    /// default, since each one spells the whole line. They are still recorded for a
+    /// debugger.
+    pub fn set_recording_rewrites(&mut self, is_recording_rewrites: bool) {
+        self.is_recording_rewrites = is_recording_rewrites;
+    }
+
+    /// Returns the macros defined so far, sorted by name.
+    pub fn definitions(&self) -> Vec<MacroDefinition> {
+        self.macros
+            .iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(name, definition)| MacroDefinition::new(name, definition))
+            .collect()
+    }
+
+    /// Returns the diagnostics reported since the last call.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.diagnostics.take()
+    }
+
+    /// Returns the steps taken since the last call, as a tree.
+    pub fn take_trace(&mut self) -> Vec<Node> {
+        self.trace.get_mut().take()
+    }
+
+    /// Returns the invocations replaced since the last call, in order, for the rewrite view.
+    pub fn take_rewrites(&mut self) -> Vec<Rewrite> {
+        std::mem::take(self.rewrites.get_mut())
+    }
+
+    fn report(&self, diagnostic: Diagnostic) {
+        self.diagnostics.borrow_mut().push(diagnostic);
+    }
+
+    /// Reports a problem with the invocation of `name`. If the invocation is produced by an
+    /// expansion, such as from a macro body, the problem is reported at the invocation
+    /// written in the source that is being expanded, as in GCC, with a note pointing at
+    /// `name`.
+    fn report_invocation(&self, message: String, name: &PpToken) {
+        let site = self.expansion_site.borrow().clone();
+        match site {
+            Some(site) if !name.hide_set.is_empty() => {
+                self.report(Diagnostic::error(message, site));
+                self.report(Diagnostic::note(
+                    format!(
+                        "macro \"{}\" is invoked here, in the expansion of a macro",
+                        name.text
+                    ),
+                    name.span(),
+                ));
+            }
+            _ => self.report(Diagnostic::error(message, name.span())),
+        }
+    }
+
+    fn is_recording(&self) -> bool {
+        self.is_recording || self.debugger.borrow().is_some()
+    }
+
+    fn is_recording_rewrites(&self) -> bool {
+        self.is_recording_rewrites || self.debugger.borrow().is_some()
+    }
+
+    /// Spells `tokens` for a step of the trace, or leaves them unspelled if it is not
+    /// recorded.
+    fn spell_for_trace(&self, tokens: &[PpToken]) -> String {
+        match self.is_recording() {
+            true => lexer::spell(tokens),
+            false => String::new(),
+        }
+    }
+
+    fn record(&self, event: Event) {
+        if self.is_recording() {
+            self.trace.borrow_mut().record(event);
+        }
+    }
+
+    /// Starts a trace node whose children are the steps recorded until `close_node`.
+    fn open_node(&self) {
+        if self.is_recording() {
+            self.trace.borrow_mut().open();
+        }
+    }
+
+    fn close_node(&self, event: Event) {
+        if self.is_recording() {
+            self.trace.borrow_mut().close(event);
+        }
+    }
+
+    /// Closes the trace node like `close_node`, or drops it if no step was recorded in it.
+    fn close_node_unless_empty(&self, event: Event) {
+        if self.is_recording() {
+            self.trace.borrow_mut().close_unless_empty(event);
         }
     }
 
@@ -30,313 +222,1552 @@ impl MacroPreprocessor {
         self.macros.remove(name);
     }
 
-    fn expand_object_macro(
+    /// Expands the macro named by the first token of `input`, if it is invoked there. The
+    /// expansion starts with a padding token standing for the macro name and ends with an
+    /// avoid-paste token. If it is expanded, the trace node of the invocation is left open
+    /// for the rescan. `output` holds the tokens already scanned.
+    fn expand_macro(&self, output: &[PpToken], input: &VecDeque<PpToken>) -> Invocation {
+        let name_token = &input[0];
+        let name = &*name_token.text;
+        let Some(definition) = self.macros.get(name) else {
+            return Invocation::None;
+        };
+        let site = Site {
+            name: name.to_string(),
+            invocation: name_token.span(),
+            definition: definition.span().clone(),
+        };
+        let (consumed, mut hide_set, body, event, line, redex) = match definition {
+            Macro::Object { body, .. } => {
+                if self.is_painted_blue(name_token, &site) {
+                    return Invocation::None;
+                }
+                self.open_node();
+                let body = self.paste_object_body(body, &site);
+                let event = Event::ObjectExpansion {
+                    site,
+                    replacement: self.spell_for_trace(&body),
+                };
+                let line = self.line_around(output, input, 1);
+                let hide_set = (*name_token.hide_set).clone();
+                (1, hide_set, body, event, line, vec![name_token.clone()])
+            }
+            Macro::Function {
+                params,
+                is_variadic,
+                body,
+                ..
+            } => {
+                // The name of a function-like macro not followed by `(` is an ordinary identifier.
+                let Some(lparen) = (1..input.len()).find(|&i| !input[i].is_padding()) else {
+                    return Invocation::None;
+                };
+                if input[lparen].kind != Token::LParen || self.is_painted_blue(name_token, &site) {
+                    return Invocation::None;
+                }
+                let variadic_index = is_variadic.then(|| params.len() - 1);
+                let Some((args, consumed)) = collect_arguments(input, lparen, variadic_index)
+                else {
+                    self.report_invocation(
+                        format!("unterminated argument list invoking macro \"{}\"", name),
+                        name_token,
+                    );
+                    return Invocation::Malformed(input.len());
+                };
+                // As in GCC, `F()` omits the variable arguments if they are the only parameter.
+                let omits_variable_arguments = *is_variadic
+                    && (args.len() == params.len() - 1
+                        || params.len() == 1 && args[0].iter().all(PpToken::is_padding));
+                let Some(args) = self.check_argument_count(name_token, params, *is_variadic, args)
+                else {
+                    return Invocation::Malformed(consumed);
+                };
+                // The expansion is hidden from the macros that both the name and the closing
+                // parenthesis are hidden from.
+                let rparen = &input[consumed - 1];
+                let hide_set = name_token
+                    .hide_set
+                    .intersection(&rparen.hide_set)
+                    .cloned()
+                    .collect();
+                self.open_node();
+                let mut substitution = Substitution {
+                    preprocessor: self,
+                    site: &site,
+                    params,
+                    is_variadic: *is_variadic,
+                    omits_variable_arguments,
+                    args: &args,
+                    expanded_args: vec![None; params.len()],
+                    invocation: (input.range(..=lparen).cloned().collect(), rparen),
+                    line: self.line_around(output, input, consumed),
+                };
+                let (body, event) = self.substitute(&mut substitution, body);
+                let redex = match self.is_recording_rewrites() {
+                    true => substitution.show_invocation(None).0,
+                    false => Vec::new(),
+                };
+                let line = substitution.line;
+                (consumed, hide_set, body, event, line, redex)
+            }
+        };
+        hide_set.insert(name_token.text.clone());
+        let hide_set = HideSet::new(hide_set);
+
+        let mut padding = name_token.clone();
+        padding.kind = Token::Padding;
+        let mut expansion = Vec::with_capacity(body.len() + 2);
+        expansion.push(padding);
+        expansion.extend(body.into_iter().map(|mut token| {
+            token.hide_set = if token.hide_set.is_empty() {
+                hide_set.clone()
+            } else {
+                HideSet::new(token.hide_set.union(&hide_set).cloned().collect())
+            };
+            token
+        }));
+        expansion.push(PpToken::new(Token::AvoidPaste, ""));
+        let rewrite = self.record_rewrite(name_token.span(), line, &redex, &expansion);
+        if let Some(debugger) = &mut *self.debugger.borrow_mut() {
+            let names = |hide_set: &HideSet| hide_set.iter().map(|name| name.to_string()).collect();
+            debugger.before_replacement(&Replacement {
+                site: event.site().unwrap().clone(),
+                depth: self.trace.borrow().depth(),
+                bindings: match &event {
+                    Event::FunctionExpansion { bindings, .. } => bindings.clone(),
+                    _ => Vec::new(),
+                },
+                hide_set: names(&name_token.hide_set),
+                replacement: lexer::spell(&expansion),
+                replacement_hide_set: names(&hide_set),
+                rewrite: rewrite.unwrap(),
+            });
+        }
+        Invocation::Expanded(consumed, expansion, event)
+    }
+
+    /// Returns the tokens of the line being scanned before the first token of `input` and
+    /// after the first `consumed` ones, in the context of the arguments being pre-expanded.
+    fn line_around(
         &self,
-        expended_macros: &mut HashSet<String>,
-        name: &str,
-    ) -> Option<String> {
-        if expended_macros.contains(name) {
-            println!("Though {} is an object-like macro, its expension is prevented by the self-reference rule", name);
-            return None;
+        output: &[PpToken],
+        input: &VecDeque<PpToken>,
+        consumed: usize,
+    ) -> (Vec<PpToken>, Vec<PpToken>) {
+        if !self.is_recording_rewrites() {
+            return (Vec::new(), Vec::new());
         }
-        if let Some(Macro::Object { body }) = self.macros.get(name) {
-            println!("Expanding object-like macro {} to `{}`", name, body);
-            expended_macros.insert(name.to_string());
-            Some(body.clone())
-        } else {
-            None
+        let contexts = self.rewrite_contexts.borrow();
+        let (before, after) = contexts
+            .last()
+            .map_or((&[][..], &[][..]), |(before, after)| {
+                (&before[..], &after[..])
+            });
+        let start = match input[0].is_line_start {
+            true => Some(output.len()),
+            false => output.iter().rposition(|token| token.is_line_start),
+        };
+        let end = input
+            .iter()
+            .skip(consumed)
+            .position(|token| token.is_line_start)
+            .map_or(input.len(), |end| consumed + end);
+        let before = before.iter().chain(&output[start.unwrap_or(0)..]);
+        let after = input.range(consumed..end).chain(after);
+        (before.cloned().collect(), after.cloned().collect())
+    }
+
+    /// Records that `redex` is replaced by `expansion` between the tokens of `line`. Returns
+    /// the rewrite with its whole line, if rewrites are recorded.
+    fn record_rewrite(
+        &self,
+        span: Span,
+        (before, after): (Vec<PpToken>, Vec<PpToken>),
+        redex: &[PpToken],
+        expansion: &[PpToken],
+    ) -> Option<Rewrite> {
+        if !self.is_recording_rewrites() {
+            return None;
         }
+        let (line, offsets) = lexer::spell_marked(
+            before.iter().chain(redex).chain(&after),
+            &[before.len(), before.len() + redex.len()],
+        );
+        let (scanned, end) = (offsets[0], offsets[1]);
+        let start = end.min(scanned + line[scanned..].len() - line[scanned..].trim_start().len());
+        let (result, _) = lexer::spell_marked(before.iter().chain(expansion).chain(&after), &[]);
+        // Only the part around the redex that differs is kept.
+        let common_prefix = line
+            .bytes()
+            .zip(result.bytes())
+            .take(start)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let common_suffix = line[end..]
+            .bytes()
+            .rev()
+            .zip(result[common_prefix..].bytes().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let rewrite = Rewrite {
+            span,
+            line: None,
+            scanned,
+            redex: start..end,
+            replaced: common_prefix..line.len() - common_suffix,
+            replacement: result[common_prefix..result.len() - common_suffix].to_string(),
+        };
+        let mut rewrites = self.rewrites.borrow_mut();
+        let previous = self.rewritten_line.replace(result);
+        let continues = !rewrites.is_empty() && line == previous;
+        rewrites.push(Rewrite {
+            line: (!continues).then(|| line.clone()),
+            ..rewrite.clone()
+        });
+        Some(Rewrite {
+            line: Some(line),
+            ..rewrite
+        })
     }
 
-    fn get_argument(params: &Vec<String>, args: &Vec<String>, arg_name: &str) -> String {
-        if arg_name == "__VA_ARGS__" {
-            args.iter().skip(params.len() - 1).join(", ")
-        } else {
-            params
+    /// Replaces the parameters in the body of a function-like macro by the arguments, then
+    /// performs the `##` operators. Returns the result and the trace event of the expansion.
+    fn substitute(
+        &self,
+        substitution: &mut Substitution,
+        body: &[PpToken],
+    ) -> (Vec<PpToken>, Event) {
+        let Substitution {
+            site,
+            params,
+            is_variadic,
+            args,
+            ..
+        } = *substitution;
+
+        // An argument is fully macro-expanded before substitution, unless the parameter is
+        // an operand of `#` or `##`.
+        let is_paste = |i: usize| body.get(i).is_some_and(|t| t.kind == Token::HashHash);
+        for (i, token) in body.iter().enumerate() {
+            let Some(index) = substitution.param_index(token) else {
+                continue;
+            };
+            if i > 0 && body[i - 1].kind == Token::Hash
+                || is_paste(i + 1)
+                || i > 0 && is_paste(i - 1)
+            {
+                self.record(Event::PrescanSkipped {
+                    site: site.clone(),
+                    param: token.text.to_string(),
+                    arg: self.spell_for_trace(&args[index]),
+                });
+            } else if substitution.expanded_args[index].is_none() {
+                substitution.expanded_args[index] = Some(self.prescan(substitution, index));
+            }
+        }
+        // Whether `__VA_OPT__` is kept depends on the expanded variable arguments.
+        if is_variadic && body.iter().any(|token| token.is_identifier("__VA_OPT__")) {
+            let index = params.len() - 1;
+            if substitution.expanded_args[index].is_none() {
+                substitution.expanded_args[index] = Some(self.prescan(substitution, index));
+            }
+        }
+
+        let (tokens, paste_left) = substitution.replace(body);
+        let result = self.paste_tokens(tokens, &paste_left, site, false);
+        let event = Event::FunctionExpansion {
+            site: site.clone(),
+            bindings: params
                 .iter()
-                .position(|p| p == arg_name)
-                .map(|i| args[i].to_string())
-                .unwrap_or(arg_name.to_string())
+                .zip(args)
+                .zip(&substitution.expanded_args)
+                .map(|((param, arg), expanded)| Binding {
+                    param: param.clone(),
+                    arg: self.spell_for_trace(expanded.as_ref().unwrap_or(arg)),
+                })
+                .collect(),
+            result: self.spell_for_trace(&result),
+        };
+        (result, event)
+    }
+
+    /// Fully macro-expands the argument at `index` before it is substituted.
+    fn prescan(&self, substitution: &Substitution, index: usize) -> Vec<PpToken> {
+        let arg = &substitution.args[index];
+        let is_recording_rewrites = self.is_recording_rewrites();
+        if is_recording_rewrites {
+            let (before, after) = substitution.show_invocation(Some(index));
+            let (line_before, line_after) = &substitution.line;
+            self.rewrite_contexts.borrow_mut().push((
+                line_before.iter().chain(&before).cloned().collect(),
+                after.iter().chain(line_after).cloned().collect(),
+            ));
+        }
+        self.open_node();
+        let expanded = self.expand(arg.to_vec());
+        self.close_node_unless_empty(Event::Prescan {
+            site: substitution.site.clone(),
+            param: substitution.params[index].clone(),
+            arg: self.spell_for_trace(arg),
+            expanded: self.spell_for_trace(&expanded),
+        });
+        if is_recording_rewrites {
+            self.rewrite_contexts.borrow_mut().pop();
         }
+        expanded
     }
 
-    fn expand_function_macro(
+    /// Checks that a function-like macro gets as many arguments as it has parameters. An
+    /// omitted variadic argument is empty.
+    fn check_argument_count(
         &self,
-        expended_macros: &mut HashSet<String>,
-        macro_name: &str,
-        macro_args: Vec<&str>,
-    ) -> Option<String> {
-        if expended_macros.contains(macro_name) {
-            println!("Though {} is a function-like macro, its expension is prevented by the self-reference rule", macro_name);
-            return None;
+        name: &PpToken,
+        params: &[String],
+        is_variadic: bool,
+        mut args: Vec<Vec<PpToken>>,
+    ) -> Option<Vec<Vec<PpToken>>> {
+        let is_empty = args.len() == 1 && args[0].iter().all(PpToken::is_padding);
+        if params.is_empty() && is_empty {
+            return Some(Vec::new());
         }
-        if let Some(Macro::Function { params, body }) = self.macros.get(macro_name) {
-            let ordered_args = if body.contains("##") {
-                println!(
-                    "The body of {} contains ##, so the pre-expansion is skipped",
-                    macro_name
-                );
-                params
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, param)| {
-                        if param == "..." {
-                            (
-                                "__VA_ARGS__".to_string(),
-                                macro_args.iter().skip(idx).join(", "),
-                            )
-                        } else {
-                            (param.to_string(), macro_args[idx].to_string())
-                        }
-                    })
-                    .collect::<Vec<(_, _)>>()
-            } else {
-                params
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, param)| {
-                        if param == "..." {
-                            (
-                                "__VA_ARGS__".to_string(),
-                                macro_args
-                                    .iter()
-                                    .skip(idx)
-                                    .map(|arg| self.process(arg))
-                                    .join(", "),
-                            )
-                        } else {
-                            (param.to_string(), self.process(macro_args[idx]))
-                        }
-                    })
-                    .collect::<Vec<(_, _)>>()
-            };
-            let args = ordered_args.iter().cloned().collect::<HashMap<_, _>>();
-
-            expended_macros.insert(macro_name.to_string());
-            let mut result = String::new();
-            let mut input = body.as_str();
-            while !input.is_empty() {
-                if let Ok((remaining, name)) = parse_stringify(input) {
-                    let arg = args.get(name).map(|a| a.as_str()).unwrap_or(name);
-                    result.push('"');
-                    result.push_str(
-                        &String::from_utf8(arg.bytes().flat_map(escape_default).collect()).unwrap(),
-                    );
-                    result.push('"');
-                    input = remaining;
-                    continue;
+        if is_variadic && args.len() == params.len() - 1 {
+            args.push(Vec::new());
+        }
+        let message = if args.len() < params.len() {
+            format!(
+                "macro \"{}\" requires {} arguments, but only {} given",
+                name.text,
+                params.len(),
+                args.len()
+            )
+        } else if args.len() > params.len() {
+            format!(
+                "macro \"{}\" passed {} arguments, but takes just {}",
+                name.text,
+                args.len(),
+                params.len()
+            )
+        } else {
+            return Some(args);
+        };
+        self.report_invocation(message, name);
+        None
+    }
+
+    /// Performs the `##` operators in the body of an object-like macro, whose operands are
+    /// the tokens around them.
+    fn paste_object_body(&self, body: &[PpToken], site: &Site) -> Vec<PpToken> {
+        let mut tokens = Vec::with_capacity(body.len());
+        let mut paste_left: Vec<bool> = Vec::with_capacity(body.len());
+        for token in body {
+            match token.kind {
+                Token::HashHash => *paste_left.last_mut().unwrap() = true,
+                _ => {
+                    tokens.push(token.clone());
+                    paste_left.push(false);
                 }
-                if let Ok((remaining, (lhs, rhs))) = parse_concatenation(input) {
-                    let larg = args.get(lhs).map(|a| a.as_str()).unwrap_or(lhs);
-                    let rarg = args.get(rhs).map(|a| a.as_str()).unwrap_or(rhs);
-                    result.push_str(&larg);
-                    result.push_str(&rarg);
-                    input = remaining;
+            }
+        }
+        self.paste_tokens(tokens, &paste_left, site, false)
+    }
+
+    /// Performs the `##` operators: each token marked in `paste_left` is concatenated with the
+    /// next one. Placemarkers left by empty arguments are removed unless `keeps_placemarkers`.
+    fn paste_tokens(
+        &self,
+        tokens: Vec<PpToken>,
+        paste_left: &[bool],
+        site: &Site,
+        keeps_placemarkers: bool,
+    ) -> Vec<PpToken> {
+        let mut result = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.into_iter().zip(paste_left.iter().copied());
+        while let Some((mut lhs, mut is_pasted)) = tokens.next() {
+            let mut has_pasted = false;
+            while is_pasted {
+                let Some((rhs, rhs_is_pasted)) = tokens.next() else {
+                    break;
+                };
+                is_pasted = rhs_is_pasted;
+                if lhs.kind == Token::Placemarker {
+                    lhs = PpToken {
+                        has_space: lhs.has_space,
+                        ..rhs
+                    };
                     continue;
                 }
-                if let Ok((remaining, name)) = identifier(input) {
-                    let arg = args.get(name).map(|a| a.as_str()).unwrap_or(name);
-                    result.push_str(&arg);
-                    input = remaining;
+                if rhs.kind == Token::Placemarker {
                     continue;
                 }
+                let text = format!("{}{}", lhs.text, rhs.text);
+                match lexer::lex_single(&text) {
+                    Some(kind) => {
+                        self.record(Event::Pasting {
+                            site: site.clone(),
+                            lhs: lhs.text.to_string(),
+                            rhs: rhs.text.to_string(),
+                            result: text.clone(),
+                        });
+                        lhs.kind = kind;
+                        lhs.text = text.into();
+                        lhs.hide_set = HideSet::new(
+                            lhs.hide_set.intersection(&rhs.hide_set).cloned().collect(),
+                        );
+                        has_pasted = true;
+                    }
+                    None => {
+                        self.report(Diagnostic::error(
+                            format!(
+                                "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                                lhs.text, rhs.text
+                            ),
+                            lhs.span(),
+                        ));
+                        result.push(std::mem::replace(&mut lhs, rhs));
+                    }
+                }
+            }
+            if keeps_placemarkers || lhs.kind != Token::Placemarker {
+                result.push(lhs);
+            }
+            if has_pasted {
+                result.push(PpToken::new(Token::AvoidPaste, ""));
+            }
+        }
+        result
+    }
 
-                result.push_str(&input[..1]);
-                input = &input[1..];
+    /// Macro-expands tokens until no more macro can be expanded.
+    fn expand(&self, tokens: Vec<PpToken>) -> Vec<PpToken> {
+        let mut input = VecDeque::from(tokens);
+        let mut result = Vec::with_capacity(input.len());
+        // The invocations whose expansions are being rescanned, from the outermost.
+        let mut rescans: Vec<Rescan> = Vec::new();
+        // Whether this call set `expansion_site`, which it clears once the invocation is
+        // rescanned.
+        let mut sets_site = false;
+        while let Some(token) = input.front() {
+            if rescans.is_empty() && token.kind == Token::Identifier {
+                let mut site = self.expansion_site.borrow_mut();
+                if site.is_none() || sets_site {
+                    *site = Some(token.span());
+                    sets_site = true;
+                }
             }
+            let invocation = if token.is_identifier("defined") {
+                self.expand_defined(&input)
+            } else if token.kind == Token::Identifier {
+                self.expand_macro(&result, &input)
+            } else {
+                Invocation::None
+            };
+            match invocation {
+                Invocation::None => {
+                    result.push(input.pop_front().unwrap());
+                    Rescan::consume(&mut rescans, 1);
+                }
+                Invocation::Expanded(consumed, expansion, event) => {
+                    input.drain(..consumed);
+                    Rescan::consume(&mut rescans, consumed);
+                    self.open_node();
+                    rescans.push(Rescan {
+                        event,
+                        tokens: self.spell_for_trace(&expansion),
+                        remaining: expansion.len(),
+                        output_start: result.len(),
+                    });
+                    for token in expansion.into_iter().rev() {
+                        input.push_front(token);
+                    }
+                }
+                Invocation::Malformed(consumed) => {
+                    result.extend(input.drain(..consumed).take(1));
+                    Rescan::consume(&mut rescans, consumed);
+                }
+                Invocation::Defined(consumed, value) => {
+                    input.drain(..consumed);
+                    result.push(value);
+                    Rescan::consume(&mut rescans, consumed);
+                }
+            }
+            // An invocation is finished once its expansion and the invocations starting in
+            // it have been rescanned.
+            while rescans.last().is_some_and(|rescan| rescan.remaining == 0) {
+                self.finish_rescan(rescans.pop().unwrap(), &result);
+            }
+        }
+        while let Some(rescan) = rescans.pop() {
+            self.finish_rescan(rescan, &result);
+        }
+        if sets_site {
+            *self.expansion_site.borrow_mut() = None;
+        }
+        // As in GCC, the macros expanded here are enabled again once every token produced by
+        // them has been scanned. Only the tokens painted blue stay unexpandable.
+        for token in &mut result {
+            if !token.hide_set.is_empty() {
+                token.hide_set = if token.hide_set.contains(&token.text) {
+                    HideSet::new(BTreeSet::from([token.text.clone()]))
+                } else {
+                    HideSet::default()
+                };
+            }
+        }
+        result
+    }
 
-            println!(
-                "Expanding function-like macro {} with args {{{}}}. The result is `{}`",
-                macro_name,
-                ordered_args
-                    .iter()
-                    .map(|(param, arg)| format!("{}=>`{}`", param, arg))
-                    .join(", "),
-                result
-            );
+    /// Closes the trace node of an invocation, with the rescan of its expansion as the last
+    /// child. `output` holds the tokens scanned so far.
+    fn finish_rescan(&self, rescan: Rescan, output: &[PpToken]) {
+        if !self.is_recording() {
+            return;
+        }
+        let site = rescan.event.site().unwrap().clone();
+        self.close_node_unless_empty(Event::Rescan {
+            site,
+            tokens: rescan.tokens,
+            result: lexer::spell(&output[rescan.output_start..]),
+        });
+        self.close_node(rescan.event);
+    }
 
-            Some(result)
-        } else {
-            None
+    /// Preprocesses source code that does not come from a file, such as the standard input.
+    /// Quoted includes are looked up relative to the current directory.
+    pub fn process_mut(&mut self, input: &str) -> String {
+        let mut output = Vec::new();
+        self.process_source(input, None, &mut output);
+        lexer::render(&output)
+    }
+
+    /// Preprocesses source code that continues earlier input in `file`, such as the lines
+    /// typed in the interactive mode, with its first line numbered `line`. Quoted includes
+    /// are looked up relative to the current directory.
+    pub fn process_continuation(&mut self, input: &str, file: &str, line: usize) -> String {
+        let file = Path::new(file);
+        let mut tokens = lexer::tokenize(input, Some(file));
+        for token in &mut tokens {
+            token.line += line - 1;
+        }
+        let mut output = Vec::new();
+        self.process_tokens(tokens, Some(file), &mut output);
+        lexer::render(&output)
+    }
+
+    /// Preprocesses the file at `path`, read through the file provider.
+    pub fn process_file(&mut self, path: impl AsRef<Path>) -> Option<String> {
+        let path = path.as_ref();
+        let contents = self.file_provider.read_file(path)?;
+        let mut output = Vec::new();
+        self.process_source(&contents, Some(path), &mut output);
+        Some(lexer::render(&output))
+    }
+
+    /// Preprocesses source code like `process_mut`, and returns the output together with
+    /// the macros defined at the end and what was collected on the way.
+    pub fn preprocess(&mut self, input: &str) -> Preprocessed {
+        let mut output = Vec::new();
+        self.process_source(input, None, &mut output);
+        self.finish(&output)
+    }
+
+    /// Preprocesses the file at `path` like `process_file`, and returns the output together
+    /// with the macros defined at the end and what was collected on the way.
+    pub fn preprocess_file(&mut self, path: impl AsRef<Path>) -> Option<Preprocessed> {
+        let path = path.as_ref();
+        let contents = self.file_provider.read_file(path)?;
+        let mut output = Vec::new();
+        self.process_source(&contents, Some(path), &mut output);
+        Some(self.finish(&output))
+    }
+
+    fn finish(&mut self, output: &[PpToken]) -> Preprocessed {
+        Preprocessed {
+            output: lexer::render(output),
+            // Every token starting a line in the source starts a line in the output.
+            line_spans: output
+                .iter()
+                .filter(|token| token.is_line_start)
+                .map(PpToken::span)
+                .collect(),
+            macros: self.definitions(),
+            diagnostics: self.take_diagnostics(),
+            trace: self.take_trace(),
+            rewrites: self.take_rewrites(),
+            kept_groups: std::mem::take(&mut self.kept_groups),
         }
     }
 
-    fn process(&self, input: &str) -> String {
-        let mut input = input.to_string();
-        let mut result = String::with_capacity(input.len());
-        let mut macro_generated_pos = 0i32;
-        let ref mut expended_macros = HashSet::new();
+    /// Defines a macro like the `-D` option of `cc`: `NAME` is defined as `1`, and
+    /// `NAME=VALUE` or `NAME(PARAMS)=VALUE` as `VALUE`.
+    pub fn define(&mut self, definition: &str) {
+        let line = match definition.split_once('=') {
+            Some((name, value)) => format!("#define {} {}\n", name, value),
+            None => format!("#define {} 1\n", definition),
+        };
+        self.process_source(&line, Some(Path::new(COMMAND_LINE)), &mut Vec::new());
+    }
+
+    /// Undefines a macro like the `-U` option of `cc`.
+    pub fn undefine(&mut self, name: &str) {
+        let line = format!("#undef {}\n", name);
+        self.process_source(&line, Some(Path::new(COMMAND_LINE)), &mut Vec::new());
+    }
+
+    fn process_source(&mut self, input: &str, file: Option<&Path>, output: &mut Vec<PpToken>) {
+        self.process_tokens(lexer::tokenize(input, file), file, output);
+    }
+
+    fn process_tokens(
+        &mut self,
+        tokens: Vec<PpToken>,
+        file: Option<&Path>,
+        output: &mut Vec<PpToken>,
+    ) {
+        // As in GCC, a literal missing its closing quote is kept as it is, even in skipped
+        // groups.
+        for token in &tokens {
+            if let Some(quote) = lexer::missing_quote(token) {
+                self.report(Diagnostic::warning(
+                    format!("missing terminating {} character", quote),
+                    token.span(),
+                ));
+            }
+        }
+        let mut text = Vec::new();
+        let mut conditionals: Vec<Conditional> = Vec::new();
 
-        while !input.is_empty() {
-            if let Ok((remaining, _)) = parse_comment(&input) {
-                let eatten = input.len() - remaining.len();
-                input.replace_range(..eatten, "");
-                macro_generated_pos -= eatten as i32;
-                if macro_generated_pos < 0 {
-                    macro_generated_pos = 0;
-                    expended_macros.clear();
+        let lines = tokens.chunk_by(|_, token| !token.is_line_start);
+        for line in lines {
+            let is_active = conditionals.last().is_none_or(|c| c.is_active);
+            if line[0].kind != Token::Hash {
+                if is_active {
+                    text.extend_from_slice(line);
+                }
+                continue;
+            }
+            // Text before a directive must be expanded with the macros defined so far.
+            output.extend(self.expand(std::mem::take(&mut text)));
+
+            let span = line[0].span();
+            let name = match line.get(1) {
+                Some(token) if token.kind == Token::Identifier => &*token.text,
+                // The null directive has no effect.
+                None => continue,
+                Some(_) => "",
+            };
+            let args = line.get(2..).unwrap_or_default();
+            match name {
+                "if" => {
+                    let is_kept = is_active && self.evaluate_condition(name, args, &span);
+                    conditionals.push(Conditional::new(span.clone(), is_active, is_kept));
                 }
-            } else if let Ok((remaining, (name, args))) = parse_macro_call(&input) {
-                if let Some(expansion) = self.expand_function_macro(expended_macros, name, args) {
-                    let eatten = input.len() - remaining.len();
-                    input.replace_range(..eatten, &expansion);
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
+                "ifdef" | "ifndef" => {
+                    let is_kept = if is_active {
+                        match parse_directive_identifier(args) {
+                            Some((macro_name, extra)) => {
+                                self.warn_extra_tokens(name, extra, &span);
+                                let definition = self.macros.get(macro_name).map(Macro::span);
+                                let is_kept = definition.is_some() == (name == "ifdef");
+                                self.record(Event::Ifdef {
+                                    span: span.clone(),
+                                    directive: name.to_string(),
+                                    name: macro_name.to_string(),
+                                    definition: definition.cloned(),
+                                    is_kept,
+                                });
+                                is_kept
+                            }
+                            None => {
+                                self.report(Diagnostic::error(
+                                    format!("#{} expects a macro name", name),
+                                    span.clone(),
+                                ));
+                                false
+                            }
+                        }
+                    } else {
+                        false
+                    };
+                    conditionals.push(Conditional::new(span.clone(), is_active, is_kept));
+                }
+                "elif" | "else" => {
+                    let Some(conditional) = conditionals.last_mut() else {
+                        self.report(Diagnostic::error(format!("#{} without #if", name), span));
+                        continue;
+                    };
+                    if conditional.has_else {
+                        let message = format!("#{} after #else", name);
+                        self.report(Diagnostic::error(message, span.clone()));
+                        let note = "the conditional began here";
+                        self.report(Diagnostic::note(note, conditional.span.clone()));
                     }
-                    macro_generated_pos += expansion.len() as i32;
-                } else {
-                    let eatten = input.len() - identifier(&input).unwrap().0.len();
-                    result.push_str(&input[..eatten]);
-                    input.replace_range(..eatten, "");
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
-                        expended_macros.clear();
+                    conditional.has_else |= name == "else";
+                    let conditional = conditionals.last().unwrap();
+                    self.end_group(conditional, Some(&span));
+                    let conditional = conditionals.last_mut().unwrap();
+                    conditional.is_active = false;
+                    if !conditional.is_parent_active {
+                        continue;
                     }
+                    if conditional.is_taken {
+                        let event = Event::GroupSkipped {
+                            span: span.clone(),
+                            directive: name.to_string(),
+                            conditional: conditional.span.clone(),
+                        };
+                        self.record(event);
+                        continue;
+                    }
+                    let conditional_span = conditional.span.clone();
+                    let is_kept = if name == "elif" {
+                        self.evaluate_condition(name, args, &span)
+                    } else {
+                        self.record(Event::ElseKept {
+                            span: span.clone(),
+                            conditional: conditional_span,
+                        });
+                        true
+                    };
+                    let conditional = conditionals.last_mut().unwrap();
+                    conditional.is_active = is_kept;
+                    conditional.is_taken = is_kept;
+                    conditional.group = span;
                 }
-            } else if let Ok((remaining, name)) = identifier(&input) {
-                if let Some(expansion) = self.expand_object_macro(expended_macros, name) {
-                    let eatten = input.len() - remaining.len();
-                    input.replace_range(..eatten, &expansion);
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
+                "endif" => match conditionals.pop() {
+                    Some(conditional) => self.end_group(&conditional, Some(&span)),
+                    None => self.report(Diagnostic::error("#endif without #if", span)),
+                },
+                _ if !is_active => {}
+                "define" => {
+                    let result = parse_define(line, self.diagnostics.get_mut());
+                    match result {
+                        Ok((name, macro_def)) => self.define_macro(name.to_string(), macro_def),
+                        Err(diagnostic) => self.report(diagnostic),
                     }
-                    macro_generated_pos += expansion.len() as i32;
-                } else {
-                    let eatten = input.len() - remaining.len();
-                    result.push_str(&input[..eatten]);
-                    input.replace_range(..eatten, "");
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
-                        expended_macros.clear();
+                }
+                "undef" => match parse_directive_identifier(args) {
+                    Some((macro_name, extra)) => {
+                        self.warn_extra_tokens(name, extra, &span);
+                        self.undefine_macro(macro_name);
+                    }
+                    None => self.report(Diagnostic::error("#undef expects a macro name", span)),
+                },
+                "include" => {
+                    let operand = self.expand_header_name(args, &span);
+                    match parse_header_name(&operand) {
+                        Ok((extra, (name, kind))) => {
+                            self.warn_extra_tokens("include", &lexer::tokenize(extra, None), &span);
+                            self.include(name, kind, span, file, output)
+                        }
+                        _ => self.report(Diagnostic::error(
+                            format!(
+                                "#include expects \"FILENAME\" or <FILENAME>, but got `{}`",
+                                operand
+                            ),
+                            span,
+                        )),
                     }
                 }
+                "pragma" if lexer::spell(args) == "once" => {
+                    if let Some(file) = file {
+                        self.once_files.insert(file.to_path_buf());
+                    }
+                }
+                "error" => self.report(Diagnostic::error(
+                    format!("#error {}", lexer::spell(args)),
+                    span,
+                )),
+                "warning" => self.report(Diagnostic::warning(
+                    format!("#warning {}", lexer::spell(args)),
+                    span,
+                )),
+                "line" => self.check_line_directive(args, span),
+                // A line marker such as `# 33 "file.c"`, which GCC accepts like #line.
+                "" if line[1].kind == Token::Number => {}
+                // Other pragmas are left to the compiler, as by `cc -E`.
+                "pragma" | "ident" | "sccs" => output.extend_from_slice(line),
+                _ => self.report(Diagnostic::error(
+                    format!("invalid preprocessing directive #{}", line[1].text),
+                    span,
+                )),
+            }
+        }
+        output.extend(self.expand(text));
+
+        for conditional in conditionals {
+            self.end_group(&conditional, None);
+            self.report(Diagnostic::error(
+                "unterminated conditional directive",
+                conditional.span,
+            ));
+        }
+    }
+
+    /// Records the current group of a conditional as kept if it is, once the directive at
+    /// `end` ends it.
+    fn end_group(&mut self, conditional: &Conditional, end: Option<&Span>) {
+        if conditional.is_active && self.is_recording() {
+            self.kept_groups.push(KeptGroup {
+                conditional: conditional.span.clone(),
+                start: conditional.group.clone(),
+                end: end.cloned(),
+            });
+        }
+    }
+
+    /// Reports the tokens after the operands of a directive, which are ignored as in GCC.
+    fn warn_extra_tokens(&self, directive: &str, extra: &[PpToken], span: &Span) {
+        if !extra.is_empty() {
+            self.report(Diagnostic::warning(
+                format!("extra tokens at end of #{} directive", directive),
+                span.clone(),
+            ));
+        }
+    }
+
+    /// Macro-expands the operands of #line and checks that they are a line number and an
+    /// optional file name. The directive does not change where tokens are reported to come
+    /// from, which is always their place in the source.
+    fn check_line_directive(&self, operands: &[PpToken], span: Span) {
+        let expanded = self.expand(operands.to_vec());
+        let mut expanded = expanded.iter().filter(|token| !token.is_padding());
+        let message = match (expanded.next(), expanded.next()) {
+            (None, _) => "unexpected end of line after #line".to_string(),
+            (Some(line), _) if !line.text.bytes().all(|c| c.is_ascii_digit()) => {
+                format!("\"{}\" after #line is not a positive integer", line.text)
+            }
+            (_, Some(file)) if file.kind != Token::StringLiteral || !file.text.starts_with('"') => {
+                format!("\"{}\" is not a valid filename", file.text)
+            }
+            _ => {
+                let extra: Vec<PpToken> = expanded.cloned().collect();
+                self.warn_extra_tokens("line", &extra, &span);
+                return;
+            }
+        };
+        self.report(Diagnostic::error(message, span));
+    }
+
+    /// Macro-expands the operand of a computed include such as `#include CONFIG_HEADER`.
+    /// An operand that starts with a header name is returned as is.
+    fn expand_header_name(&self, operand: &[PpToken], span: &Span) -> String {
+        let spelling = lexer::spell(operand);
+        if operand
+            .first()
+            .is_some_and(|token| token.kind == Token::HeaderName)
+        {
+            return spelling;
+        }
+        self.open_node();
+        let expanded = lexer::spell(&self.expand(operand.to_vec()));
+        self.close_node(Event::HeaderNameExpansion {
+            span: span.clone(),
+            operand: spelling,
+            header_name: expanded.clone(),
+        });
+        expanded
+    }
+
+    /// Looks up an included file in the include paths and preprocesses it.
+    fn include(
+        &mut self,
+        name: &str,
+        kind: HeaderKind,
+        span: Span,
+        current_file: Option<&Path>,
+        output: &mut Vec<PpToken>,
+    ) {
+        let spelling = match kind {
+            HeaderKind::Quoted => format!("\"{}\"", name),
+            HeaderKind::Angled => format!("<{}>", name),
+        };
+        let current_dir = current_file.and_then(Path::parent).unwrap_or(Path::new(""));
+        let candidates = self.include_paths.candidates(name, kind, current_dir);
+        let Some((path, contents)) = candidates.iter().find_map(|path| {
+            self.file_provider
+                .read_file(path)
+                .map(|contents| (path, contents))
+        }) else {
+            let searched = if candidates.is_empty() {
+                "no include directory is configured".to_string()
             } else {
-                let eatten = 1;
-                result.push_str(&input[..eatten]);
-                input.replace_range(..eatten, "");
-                macro_generated_pos -= eatten as i32;
-                if macro_generated_pos < 0 {
-                    macro_generated_pos = 0;
-                    expended_macros.clear();
+                format!(
+                    "searched {}",
+                    candidates
+                        .iter()
+                        .map(|path| format!("`{}`", path.display()))
+                        .join(", ")
+                )
+            };
+            self.report(Diagnostic::error(
+                format!("{} is not found, {}", spelling, searched),
+                span,
+            ));
+            return;
+        };
+        if self.once_files.contains(path) {
+            self.record(Event::Include {
+                span,
+                header_name: spelling,
+                path: path.clone(),
+                is_skipped: true,
+            });
+            return;
+        }
+        if self.include_depth >= MAX_INCLUDE_DEPTH {
+            self.report(Diagnostic::error(
+                format!(
+                    "#include nested depth {} exceeds maximum of {}",
+                    self.include_depth, MAX_INCLUDE_DEPTH
+                ),
+                span,
+            ));
+            return;
+        }
+        let path = path.clone();
+        self.open_node();
+        self.include_depth += 1;
+        self.process_source(&contents, Some(&path), output);
+        self.include_depth -= 1;
+        self.close_node(Event::Include {
+            span,
+            header_name: spelling,
+            path,
+            is_skipped: false,
+        });
+    }
+
+    /// Whether a macro name is in its own hide set, in which case it is never expanded.
+    fn is_painted_blue(&self, name: &PpToken, site: &Site) -> bool {
+        if !name.hide_set.contains(&name.text) {
+            return false;
+        }
+        self.record(Event::SelfReference {
+            site: site.clone(),
+            hide_set: name.hide_set.iter().map(|name| name.to_string()).collect(),
+        });
+        true
+    }
+
+    /// Replaces every `defined X` and `defined(X)` in a condition by `1` or `0`.
+    /// This must happen before macro expansion, so that `X` itself is not expanded.
+    fn replace_defined(&self, condition: &[PpToken]) -> Vec<PpToken> {
+        let mut result = Vec::with_capacity(condition.len());
+        let mut i = 0;
+        while i < condition.len() {
+            let Some((macro_name, len)) = parse_defined(&condition[i..]) else {
+                result.push(condition[i].clone());
+                i += 1;
+                continue;
+            };
+            let operator = &condition[i..i + len];
+            result.push(self.defined_value(operator, macro_name, operator[0].span()));
+            i += len;
+        }
+        result
+    }
+
+    /// Returns the value of a `defined` operator as a number token.
+    fn defined_value(&self, operator: &[PpToken], name: &str, span: Span) -> PpToken {
+        let definition = self.macros.get(name).map(Macro::span);
+        self.record(Event::Defined {
+            span,
+            operator: lexer::spell(operator),
+            name: name.to_string(),
+            definition: definition.cloned(),
+        });
+        let mut token = PpToken::new(Token::Number, &(definition.is_some() as i64).to_string());
+        token.has_space = operator[0].has_space;
+        token
+    }
+
+    /// Evaluates a `defined` operator at the front of `input`, which a macro produced in a
+    /// condition. Its operand is not macro-expanded, and GCC warns that other compilers may
+    /// not evaluate it.
+    fn expand_defined(&self, input: &VecDeque<PpToken>) -> Invocation {
+        let Some(span) = self.condition.borrow().clone() else {
+            return Invocation::None;
+        };
+        // Substituted arguments may leave padding inside the operator.
+        let operator: Vec<(usize, &PpToken)> = input
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| !token.is_padding())
+            .take(4)
+            .collect();
+        let tokens: Vec<PpToken> = operator.iter().map(|(_, token)| (*token).clone()).collect();
+        let Some((name, len)) = parse_defined(&tokens) else {
+            self.report(Diagnostic::error(
+                "operator \"defined\" requires an identifier",
+                span,
+            ));
+            return Invocation::None;
+        };
+        self.report(Diagnostic::warning(
+            "this use of \"defined\" may not be portable",
+            span.clone(),
+        ));
+        let value = self.defined_value(&tokens[..len], name, span);
+        Invocation::Defined(operator[len - 1].0 + 1, value)
+    }
+
+    /// Macro-expands the controlling expression of `#if` or `#elif` and evaluates it,
+    /// returning whether the group is kept. Malformed expressions are reported and treated
+    /// as false.
+    fn evaluate_condition(&self, directive: &str, condition: &[PpToken], span: &Span) -> bool {
+        self.open_node();
+        *self.condition.borrow_mut() = Some(span.clone());
+        let expanded = self.expand(self.replace_defined(condition));
+        *self.condition.borrow_mut() = None;
+        let spelling = self.spell_for_trace(&expanded);
+        if spelling != self.spell_for_trace(condition) {
+            self.record(Event::ConditionExpanded {
+                span: span.clone(),
+                condition: spelling,
+            });
+        }
+        let mut steps = Vec::new();
+        let is_function_like =
+            |name: &str| matches!(self.macros.get(name), Some(Macro::Function { .. }));
+        let value =
+            expr::evaluate(&expanded, &is_function_like, &mut steps).unwrap_or_else(|err| {
+                self.report(Diagnostic::error(err, span.clone()));
+                Value::Signed(0)
+            });
+        for step in steps {
+            self.record(Event::Evaluation {
+                span: span.clone(),
+                step,
+            });
+        }
+        self.close_node(Event::Condition {
+            span: span.clone(),
+            directive: directive.to_string(),
+            condition: lexer::spell(condition),
+            value: value.to_string(),
+            is_kept: value.is_true(),
+        });
+        value.is_true()
+    }
+}
+
+/// The arguments of a function-like macro invocation, ready to be substituted into the body.
+struct Substitution<'a> {
+    preprocessor: &'a MacroPreprocessor,
+    site: &'a Site,
+    params: &'a [String],
+    is_variadic: bool,
+    /// Whether the invocation omits the variable arguments, rather than giving empty ones.
+    omits_variable_arguments: bool,
+    args: &'a [Vec<PpToken>],
+    /// The fully macro-expanded arguments of the parameters that need them.
+    expanded_args: Vec<Option<Vec<PpToken>>>,
+    /// The tokens of the invocation up to `(`, and its `)`.
+    invocation: (Vec<PpToken>, &'a PpToken),
+    /// The tokens of the line around the invocation, for the rewrite view.
+    line: (Vec<PpToken>, Vec<PpToken>),
+}
+
+impl Substitution<'_> {
+    /// Returns the tokens of the invocation with the arguments pre-expanded so far, split
+    /// around the argument at `index` if any.
+    fn show_invocation(&self, index: Option<usize>) -> (Vec<PpToken>, Vec<PpToken>) {
+        let (head, rparen) = &self.invocation;
+        let mut before = head.clone();
+        let mut after = Vec::new();
+        let mut args = self.args.len();
+        if self.omits_variable_arguments && self.args[args - 1].is_empty() {
+            args -= 1;
+        }
+        for i in 0..args {
+            let tokens = match index.is_some_and(|index| i <= index) {
+                true => &mut before,
+                false => &mut after,
+            };
+            if i > 0 {
+                tokens.push(PpToken::new(Token::Comma, ","));
+            }
+            if Some(i) != index {
+                tokens.extend(
+                    self.expanded_args[i]
+                        .as_ref()
+                        .unwrap_or(&self.args[i])
+                        .iter()
+                        .cloned(),
+                );
+            }
+        }
+        after.push((*rparen).clone());
+        match index {
+            Some(_) => (before, after),
+            None => (before.into_iter().chain(after).collect(), Vec::new()),
+        }
+    }
+
+    fn param_index(&self, token: &PpToken) -> Option<usize> {
+        if token.kind != Token::Identifier {
+            return None;
+        }
+        self.params.iter().position(|param| *param == *token.text)
+    }
+
+    /// Finds the end of the `__VA_OPT__ ( ... )` starting at `start` in the body, if any.
+    fn va_opt_end(&self, body: &[PpToken], start: usize) -> Option<usize> {
+        if !self.is_variadic || !body[start].is_identifier("__VA_OPT__") {
+            return None;
+        }
+        find_va_opt_end(body, start)
+    }
+
+    /// Replaces `__VA_OPT__ ( content )` by the substituted content if the variable
+    /// arguments are present, that is, if they expand to at least one token.
+    fn replace_va_opt(&self, body: &[PpToken], start: usize, end: usize) -> Vec<PpToken> {
+        let index = self.params.len() - 1;
+        let is_present = self.expanded_args[index]
+            .as_ref()
+            .is_some_and(|arg| arg.iter().any(|token| !token.is_padding()));
+        let va_opt = lexer::spell(&body[start..=end]);
+        if !is_present {
+            self.preprocessor.record(Event::VaOpt {
+                site: self.site.clone(),
+                va_opt,
+                variable_arguments: lexer::spell(&self.args[index]),
+                replacement: None,
+            });
+            return Vec::new();
+        }
+        // A placemarker at either end stays an operand of a `##` around `__VA_OPT__`, as in
+        // `__VA_OPT__(a X ## X) ## b`, which gives `a b` if X is empty.
+        let (tokens, paste_left) = self.replace(&body[start + 2..end]);
+        let result = self
+            .preprocessor
+            .paste_tokens(tokens, &paste_left, self.site, true);
+        self.preprocessor.record(Event::VaOpt {
+            site: self.site.clone(),
+            va_opt,
+            variable_arguments: lexer::spell(&self.args[index]),
+            replacement: Some(lexer::spell(&result)),
+        });
+        result
+    }
+
+    /// Handles the GNU extension `, ## args`: the comma is removed if the variable arguments
+    /// are omitted, and is kept without pasting otherwise.
+    fn swallow_comma(
+        &self,
+        tokens: &mut Vec<PpToken>,
+        paste_left: &mut Vec<bool>,
+        param: &PpToken,
+        arg: &[PpToken],
+    ) {
+        let variable_arguments = if self.omits_variable_arguments {
+            tokens.pop();
+            paste_left.pop();
+            None
+        } else {
+            *paste_left.last_mut().unwrap() = false;
+            Some(lexer::spell(arg))
+        };
+        self.preprocessor.record(Event::CommaElision {
+            site: self.site.clone(),
+            param: param.text.to_string(),
+            variable_arguments,
+        });
+    }
+
+    /// Applies the `#` operator to `operand`. As in GCC, an odd number of backslashes at the
+    /// end, which would escape the closing quote, loses its last one.
+    fn stringify(&self, operand: &[PpToken]) -> PpToken {
+        let (spelling, mut string) = stringify(operand);
+        let backslashes: Vec<&PpToken> = operand
+            .iter()
+            .filter(|token| !token.is_padding() && token.kind != Token::Placemarker)
+            .rev()
+            .take_while(|token| token.kind == Token::Other && token.text.starts_with('\\'))
+            .collect();
+        if backslashes.len() % 2 == 1 {
+            self.preprocessor.report(Diagnostic::warning(
+                "invalid string literal, ignoring final '\\'",
+                backslashes[0].span(),
+            ));
+            string.text = format!("{}\"", &string.text[..string.text.len() - 2]).into();
+        }
+        self.preprocessor.record(Event::Stringification {
+            site: self.site.clone(),
+            operand: spelling,
+            result: string.text.to_string(),
+        });
+        string
+    }
+
+    /// Substitutes the arguments into the body. Tokens to be pasted with the next token by
+    /// `##` are marked in the returned flags.
+    fn replace(&self, body: &[PpToken]) -> (Vec<PpToken>, Vec<bool>) {
+        let is_paste = |i: usize| body.get(i).is_some_and(|t| t.kind == Token::HashHash);
+        let mut tokens = Vec::with_capacity(body.len());
+        let mut paste_left = Vec::with_capacity(body.len());
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+            if token.kind == Token::HashHash {
+                if let Some(last) = paste_left.last_mut() {
+                    *last = true;
+                }
+                i += 1;
+                continue;
+            }
+            if token.kind == Token::Hash && i + 1 < body.len() {
+                let operand = if let Some(index) = self.param_index(&body[i + 1]) {
+                    Some((Cow::Borrowed(&self.args[index]), i + 1))
+                } else {
+                    self.va_opt_end(body, i + 1)
+                        .map(|end| (Cow::Owned(self.replace_va_opt(body, i + 1, end)), end))
+                };
+                if let Some((operand, end)) = operand {
+                    let mut string = self.stringify(&operand);
+                    string.has_space = token.has_space;
+                    tokens.push(string);
+                    paste_left.push(false);
+                    i = end + 1;
+                    continue;
                 }
             }
+
+            let is_lhs_pasted = i > 0 && is_paste(i - 1);
+            let (arg, end) = if let Some(index) = self.param_index(token) {
+                let arg = match &self.expanded_args[index] {
+                    Some(expanded) if !is_lhs_pasted && !is_paste(i + 1) => expanded,
+                    _ => &self.args[index],
+                };
+                if is_lhs_pasted
+                    && self.is_variadic
+                    && index == self.params.len() - 1
+                    && body[i - 2].kind == Token::Comma
+                {
+                    self.swallow_comma(&mut tokens, &mut paste_left, token, arg);
+                }
+                (Cow::Borrowed(arg.as_slice()), i)
+            } else if let Some(end) = self.va_opt_end(body, i) {
+                (Cow::Owned(self.replace_va_opt(body, i, end)), end)
+            } else {
+                tokens.push(token.clone());
+                paste_left.push(false);
+                i += 1;
+                continue;
+            };
+            let is_rhs_pasted = is_paste(end + 1);
+
+            // Padding around an argument keeps it apart from its neighbours in the output,
+            // except on the sides of `##`.
+            if i > 0 && !is_lhs_pasted {
+                let mut padding = token.clone();
+                padding.kind = Token::Padding;
+                tokens.push(padding);
+                paste_left.push(false);
+            }
+            let arg = if is_lhs_pasted || is_rhs_pasted {
+                let start = arg
+                    .iter()
+                    .position(|t| !t.is_padding())
+                    .unwrap_or(arg.len());
+                let end = arg
+                    .iter()
+                    .rposition(|t| !t.is_padding())
+                    .map_or(start, |end| end + 1);
+                &arg[start..end]
+            } else {
+                &arg[..]
+            };
+            if arg.is_empty() && (is_lhs_pasted || is_rhs_pasted) {
+                let mut placemarker = PpToken::new(Token::Placemarker, "");
+                placemarker.has_space = token.has_space;
+                tokens.push(placemarker);
+                paste_left.push(false);
+            } else {
+                tokens.extend(arg.iter().cloned());
+                paste_left.extend(arg.iter().map(|_| false));
+            }
+            if !is_rhs_pasted {
+                tokens.push(PpToken::new(Token::AvoidPaste, ""));
+                paste_left.push(false);
+            }
+            i = end + 1;
+        }
+        (tokens, paste_left)
+    }
+}
+
+/// Spells tokens as a string literal for the `#` operator, following C17 6.10.3.2 as GCC
+/// does. Whitespace between the tokens becomes a single space, and only `"` and `\` in
+/// string literals and character constants are escaped. Padding only carries whitespace:
+/// unlike in the output, no space is added to keep tokens apart.
+fn stringify(tokens: &[PpToken]) -> (String, PpToken) {
+    let mut spelling = String::new();
+    let mut contents = String::new();
+    // The whitespace of the token that started the pending padding, if any.
+    let mut source: Option<bool> = None;
+    let mut is_first = true;
+    for token in tokens {
+        match token.kind {
+            Token::Padding => {
+                source.get_or_insert(token.has_space);
+                continue;
+            }
+            Token::AvoidPaste => {
+                if source == Some(false) {
+                    source = None;
+                }
+                continue;
+            }
+            Token::Placemarker => continue,
+            _ => {}
+        }
+        if !is_first && source.unwrap_or(token.has_space) {
+            spelling.push(' ');
+            contents.push(' ');
+        }
+        spelling.push_str(&token.text);
+        if matches!(token.kind, Token::StringLiteral | Token::CharLiteral) {
+            for c in token.text.chars() {
+                if matches!(c, '"' | '\\') {
+                    contents.push('\\');
+                }
+                contents.push(c);
+            }
+        } else {
+            contents.push_str(&token.text);
+        }
+        is_first = false;
+        source = None;
+    }
+    let literal = PpToken::new(Token::StringLiteral, &format!("\"{}\"", contents));
+    (spelling, literal)
+}
+
+/// What `expand` does with the tokens at the front of its input.
+enum Invocation {
+    /// The first token is not a macro invocation, and is kept as is.
+    None,
+    /// The invocation made of this many tokens is replaced by its expansion, which is
+    /// explained by the event.
+    Expanded(usize, Vec<PpToken>, Event),
+    /// The invocation made of this many tokens is malformed. Only the macro name is kept,
+    /// as in GCC.
+    Malformed(usize),
+    /// The `defined` operator made of this many tokens, produced by a macro in a condition,
+    /// is replaced by its value.
+    Defined(usize, PpToken),
+}
+
+/// An invocation whose expansion is being rescanned.
+struct Rescan {
+    event: Event,
+    tokens: String,
+    /// The number of tokens of the expansion still in the input. They come right after the
+    /// remaining tokens of the invocations rescanned inside this one.
+    remaining: usize,
+    /// The number of tokens already scanned when the rescan starts.
+    output_start: usize,
+}
+
+impl Rescan {
+    /// Removes tokens scanned from the front of the input, innermost invocation first.
+    fn consume(rescans: &mut [Rescan], mut count: usize) {
+        for rescan in rescans.iter_mut().rev() {
+            let scanned = count.min(rescan.remaining);
+            rescan.remaining -= scanned;
+            count -= scanned;
+        }
+    }
+}
+
+/// Collects the arguments of a function-like macro invocation whose `(` is at `lparen`.
+/// Returns the arguments and the number of tokens up to and including the closing `)`, or
+/// `None` if the invocation is unterminated. Arguments matching the variadic parameter at
+/// `variadic_index` and after it are collected as one, commas included.
+fn collect_arguments(
+    input: &VecDeque<PpToken>,
+    lparen: usize,
+    variadic_index: Option<usize>,
+) -> Option<(Vec<Vec<PpToken>>, usize)> {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    for (i, token) in input.iter().enumerate().skip(lparen + 1) {
+        match token.kind {
+            Token::LParen => depth += 1,
+            Token::RParen if depth == 0 => {
+                for arg in &mut args {
+                    while arg.last().is_some_and(PpToken::is_padding) {
+                        arg.pop();
+                    }
+                }
+                return Some((args, i + 1));
+            }
+            Token::RParen => depth -= 1,
+            Token::Comma if depth == 0 && variadic_index != Some(args.len() - 1) => {
+                args.push(Vec::new());
+                continue;
+            }
+            _ => {}
+        }
+        let arg = args.last_mut().unwrap();
+        if arg.is_empty() && token.is_padding() {
+            continue;
+        }
+        let mut token = token.clone();
+        // An invocation spanning several lines is printed on one line.
+        if token.is_line_start {
+            token.is_line_start = false;
+            token.has_space = true;
+        }
+        arg.push(token);
+    }
+    None
+}
+
+/// The state of one `#if` ... `#endif` block while its groups are being processed.
+#[derive(Debug)]
+struct Conditional {
+    /// Where the `#if` that opened the conditional is.
+    span: Span,
+    /// Whether the enclosing group is kept. If not, every group of this conditional is skipped.
+    is_parent_active: bool,
+    /// Whether the current group is kept.
+    is_active: bool,
+    /// Where the directive that starts the current group is.
+    group: Span,
+    /// Whether one of the groups has already been kept.
+    is_taken: bool,
+    has_else: bool,
+}
+
+impl Conditional {
+    fn new(span: Span, is_parent_active: bool, is_active: bool) -> Self {
+        Self {
+            group: span.clone(),
+            span,
+            is_parent_active,
+            is_active,
+            is_taken: is_active,
+            has_else: false,
         }
+    }
+}
 
-        result
+/// A group of a conditional that is kept, such as the lines between `#if 1` and `#else`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeptGroup {
+    /// Where the `#if`, `#ifdef` or `#ifndef` that opens the conditional is.
+    pub conditional: Span,
+    /// Where the directive that keeps the group is.
+    pub start: Span,
+    /// Where the directive that ends the group is, or `None` if the conditional is
+    /// unterminated.
+    pub end: Option<Span>,
+}
+
+/// A macro definition as written in its `#define`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MacroDefinition {
+    pub name: String,
+    /// The parameters of a function-like macro, or `None` for an object-like macro. The
+    /// variadic parameter is named `__VA_ARGS__` for `...`.
+    pub params: Option<Vec<String>>,
+    pub is_variadic: bool,
+    pub body: String,
+    pub span: Span,
+}
+
+impl MacroDefinition {
+    fn new(name: &str, definition: &Macro) -> Self {
+        let (params, is_variadic, body) = match definition {
+            Macro::Object { body, .. } => (None, false, body),
+            Macro::Function {
+                params,
+                is_variadic,
+                body,
+                ..
+            } => (Some(params.clone()), *is_variadic, body),
+        };
+        Self {
+            name: name.to_string(),
+            params,
+            is_variadic,
+            body: lexer::spell(body),
+            span: definition.span().clone(),
+        }
     }
+}
 
-    fn process_mut(&mut self, input: &str) -> String {
-        let mut input = input.to_string();
-        let mut result = String::with_capacity(input.len());
-        let mut macro_generated_pos = 0i32;
-        let ref mut expended_macros = HashSet::new();
-
-        while !input.is_empty() {
-            if let Ok((remaining, _)) = parse_comment(&input) {
-                let eatten = input.len() - remaining.len();
-                input.replace_range(..eatten, "");
-                macro_generated_pos -= eatten as i32;
-                if macro_generated_pos < 0 {
-                    macro_generated_pos = 0;
-                    expended_macros.clear();
-                }
-            } else if let Ok((remaining, name)) = parse_undef(&input) {
-                self.undefine_macro(name);
-                let eatten = input.len() - remaining.len();
-                input.replace_range(..eatten + 1, ""); // the new line
-                macro_generated_pos -= eatten as i32;
-                if macro_generated_pos < 0 {
-                    macro_generated_pos = 0;
-                    expended_macros.clear();
-                }
-            } else if let Ok((remaining, (name, macro_def))) = parse_define(&input) {
-                self.define_macro(name.to_string(), macro_def);
-                let eatten = input.len() - remaining.len();
-                input.replace_range(..eatten + 1, ""); // the new line
-                macro_generated_pos -= eatten as i32;
-                if macro_generated_pos < 0 {
-                    macro_generated_pos = 0;
-                    expended_macros.clear();
-                }
-            } else if let Ok((remaining, (name, args))) = parse_macro_call(&input) {
-                if let Some(expansion) = self.expand_function_macro(expended_macros, name, args) {
-                    // result.push_str(&expansion);
-                    let eatten = input.len() - remaining.len();
-                    input.replace_range(..eatten, &expansion);
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
-                    }
-                    macro_generated_pos += expansion.len() as i32;
-                } else {
-                    let eatten = input.len() - identifier(&input).unwrap().0.len();
-                    result.push_str(&input[..eatten]);
-                    input.replace_range(..eatten, "");
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
-                        expended_macros.clear();
-                    }
-                }
-            } else if let Ok((remaining, name)) = identifier(&input) {
-                if let Some(expansion) = self.expand_object_macro(expended_macros, name) {
-                    // result.push_str(&expansion);
-                    let eatten = input.len() - remaining.len();
-                    input.replace_range(..eatten, &expansion);
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
-                    }
-                    macro_generated_pos += expansion.len() as i32;
-                } else {
-                    let eatten = input.len() - remaining.len();
-                    result.push_str(&input[..eatten]);
-                    input.replace_range(..eatten, "");
-                    macro_generated_pos -= eatten as i32;
-                    if macro_generated_pos < 0 {
-                        macro_generated_pos = 0;
-                        expended_macros.clear();
-                    }
-                }
-            } else {
-                let eatten = 1;
-                result.push_str(&input[..eatten]);
-                input.replace_range(..eatten, "");
-                macro_generated_pos -= eatten as i32;
-                if macro_generated_pos < 0 {
-                    macro_generated_pos = 0;
-                    expended_macros.clear();
-                }
+/// Prints the definition as a `#define` line.
+impl fmt::Display for MacroDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#define {}", self.name)?;
+        if let Some(params) = &self.params {
+            let mut params = params.clone();
+            if let Some(last) = params.last_mut().filter(|_| self.is_variadic) {
+                *last = match last.as_str() {
+                    "__VA_ARGS__" => "...".to_string(),
+                    name => format!("{}...", name),
+                };
             }
+            write!(f, "({})", params.join(", "))?;
         }
-
-        result
+        if !self.body.is_empty() {
+            write!(f, " {}", self.body)?;
+        }
+        Ok(())
     }
 }
 
-pub fn preprocess(input: &str) -> String {
-    let mut preprocessor = MacroPreprocessor::new();
-    preprocessor.process_mut(input)
+/// The result of preprocessing a translation unit.
+#[derive(Debug, Clone, Serialize)]
+pub struct Preprocessed {
+    pub output: String,
+    /// Where each line of the output starts in the source.
+    pub line_spans: Vec<Span>,
+    /// The macros defined at the end.
+    pub macros: Vec<MacroDefinition>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The steps taken to produce the output, as a tree.
+    pub trace: Vec<Node>,
+    /// The invocations replaced to produce the output, in order, if they are recorded. They
+    /// are left out of the JSON, where they would spell each line once per invocation.
+    #[serde(skip)]
+    pub rewrites: Vec<Rewrite>,
+    /// The groups of conditionals that are kept, if the steps are recorded. They tell which
+    /// steps of the trace keep a line of the output.
+    #[serde(skip)]
+    pub kept_groups: Vec<KeptGroup>,
+}
+
+/// Preprocesses source code read from the standard input, with included files read from the
+/// file system.
+pub fn preprocess(input: &str) -> Preprocessed {
+    MacroPreprocessor::new().preprocess(input)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::{Read, Write};
+    use std::io::Write;
     use std::process::Stdio;
+    use std::rc::Rc;
 
     use super::*;
+    use crate::{standardize, InMemoryFiles};
 
     fn get_reference_result(source_code: &str) -> Option<String> {
+        get_reference_result_in(source_code, &std::env::current_dir().unwrap(), &[])
+    }
+
+    fn get_reference_result_in(source_code: &str, dir: &Path, args: &[&str]) -> Option<String> {
         std::env::set_var("OPT_LEVEL", "0");
         std::env::set_var("TARGET", "x86_64-unknown-linux-gnu");
         std::env::set_var("HOST", "x86_64-unknown-linux-gnu");
 
         let tool = cc::Build::new().get_compiler();
         let mut command = tool.to_command();
+        command.current_dir(dir).args(args);
         command.arg("-E").arg("-P").arg("-");
         let mut child = command
             .stdin(Stdio::piped())
@@ -356,20 +1787,8 @@ mod tests {
         }
     }
 
-    fn standardize(mut s: String) -> String {
-        loop {
-            let mut new = s.replace("\n\n", "\n");
-            new = new.replace(" \n", "\n");
-            if new.len() == s.len() {
-                break;
-            }
-            s = new;
-        }
-        s.trim().to_string()
-    }
-
     fn test(source_code: &str) {
-        let expanded_code = preprocess(source_code);
+        let expanded_code = preprocess(source_code).output;
         let expanded_code = standardize(expanded_code);
         let reference = get_reference_result(source_code).unwrap();
         let reference = standardize(reference);
@@ -381,6 +1800,44 @@ mod tests {
         );
     }
 
+    /// Compares the result against the compiler with `files` available to `#include`.
+    /// The explainer reads them from memory, while the compiler reads them from a
+    /// temporary directory.
+    fn test_with_files(source_code: &str, files: &[(&str, &str)]) {
+        let dir = std::env::temp_dir().join(format!(
+            "c-macro-explainer-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let mut memory = InMemoryFiles::new();
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            memory.add_file(path, *contents);
+        }
+
+        let main = dir.join("main.c");
+        std::fs::write(&main, source_code).unwrap();
+        memory.add_file(&main, source_code);
+
+        let mut preprocessor = MacroPreprocessor::with_file_provider(memory);
+        preprocessor.add_user_include_dir(dir.join("include"));
+        preprocessor.add_system_include_dir(dir.join("system"));
+        let expanded_code = preprocessor.process_file(&main).unwrap();
+        let expanded_code = standardize(expanded_code);
+        let reference =
+            get_reference_result_in(source_code, &dir, &["-I", "include", "-isystem", "system"])
+                .unwrap();
+        let reference = standardize(reference);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            expanded_code, reference,
+            "Source Code:\n{}\n\nExpanded Code:\n{}\n\nReference:\n{}",
+            source_code, expanded_code, reference,
+        );
+    }
+
     #[test]
     fn simple_replacing() {
         test(
@@ -493,6 +1950,20 @@ WHEN(xxx)(xxx)
         );
     }
 
+    #[test]
+    fn object_concatenation() {
+        test(
+            r#"
+#define OBJECT A ## B ## 1 OBJECT
+OBJECT
+#define PASTED_NAME OBJ ## ECT
+PASTED_NAME
+#define A_1 a ## 1 + ## +
+A_1
+"#,
+        );
+    }
+
     #[test]
     fn recursion() {
         test(
@@ -617,6 +2088,63 @@ EQUAL(unfoo, unfoo) // Expands to 0
         );
     }
 
+    #[test]
+    fn va_opt() {
+        test(
+            r#"
+#define F(a, ...) f(a __VA_OPT__(,) __VA_ARGS__)
+#define E
+#define G(...) [__VA_OPT__(x y)]
+#define S(...) #__VA_OPT__(a   b __VA_ARGS__)
+#define P(x, ...) x ## __VA_OPT__(_ ## __VA_ARGS__) ## end
+#define Q(...) __VA_OPT__(__VA_ARGS__ ## z)
+#define EMPTY_P(...) a __VA_OPT__() b
+F(1) F(1,) F(1, 2) F(1, 2, 3) F(1, E)
+G() G(E) G( ) G(,)
+S() S(1) S(E)
+P(p) P(p, q) P(, q) P(p,)
+Q() Q(y) Q(E)
+EMPTY_P(1)
+
+#define SF(...) f(0 __VA_OPT__(,) __VA_ARGS__)
+#define SG(X, ...) f(0, X __VA_OPT__(,) __VA_ARGS__)
+#define SDEF(sname, ...) S sname __VA_OPT__(= { __VA_ARGS__ })
+SF(a,b,c) SF() SF(E)
+SG(a,b,c) SG(a,) SG(a)
+SDEF(foo); SDEF(bar, 1, 2);
+#define H2(X, Y, ...) __VA_OPT__(X ## Y,) __VA_ARGS__
+H2(a, b, c, d)
+#define H3(X, ...) #__VA_OPT__(X##X X##X)
+H3(, 0)
+#define H4(X, ...) __VA_OPT__(a X ## X) ## b
+H4(, 1) H4(x, 1) H4(,)
+#define H5A(...) __VA_OPT__()/**/__VA_OPT__()
+#define H5B(X) a ## X ## b
+#define H5C(X) H5B(X)
+H5C(H5A())
+"#,
+        );
+    }
+
+    #[test]
+    fn gnu_variadic() {
+        test(
+            r#"
+#define LOG(fmt, args...) printf(fmt, ## args)
+#define ELOG(fmt, ...) printf(fmt,##__VA_ARGS__)
+#define NAMED(args...) f(args) g(#args)
+#define X 1
+LOG("a") LOG("a",) LOG("a", 1) LOG("a", X, 2) LOG("a",  X)
+ELOG("b") ELOG("b", X) ELOG("b", ) ELOG("b",x,y)
+NAMED() NAMED(1, 2) NAMED(X)
+#define ONLY(...) [a, ## __VA_ARGS__]
+ONLY() ONLY(1)
+#define MID(a, b...) (a , ## b , end)
+MID(1) MID(1, 2)
+"#,
+        );
+    }
+
     #[test]
     fn self_reference() {
         test(
@@ -635,6 +2163,43 @@ y
         );
     }
 
+    #[test]
+    fn hide_set() {
+        test(
+            r#"
+#define f(x) x f
+f(1)(2)
+#define g f
+#define h(x) x g
+h(1)(2)
+#define EMPTY()
+#define DEFER(id) id EMPTY()
+#define OBSTRUCT(...) __VA_ARGS__ DEFER(EMPTY)()
+#define EXPAND(...) __VA_ARGS__
+#define A() 123 B
+#define B() A
+A()()()
+DEFER(A)()
+EXPAND(DEFER(A)())
+EXPAND(OBSTRUCT(A)())
+EXPAND(EXPAND(OBSTRUCT(A)()))
+#define foo foo
+EXPAND(foo) EXPAND(EXPAND(foo))
+#define NIL(x) x
+#define G_0(arg) NIL(G_1)(arg)
+#define G_1(arg) NIL(arg)
+G_0(42)
+#define q(x) x
+#define r(x) q(x) r
+q(r(1))(2)
+EXPAND(r(1)(2))
+#define RECURSE() x R_INDIRECT EMPTY()()
+#define R_INDIRECT() RECURSE
+RECURSE() EXPAND(RECURSE()) EXPAND(EXPAND(RECURSE()))
+"#,
+        );
+    }
+
     #[test]
     fn prescan() {
         test(
@@ -656,4 +2221,839 @@ XAFTERX(BUFSIZE)
 "#,
         );
     }
+
+    #[test]
+    fn conditional() {
+        test(
+            r#"
+#define ONE 1
+#define ZERO 0
+#if ONE
+one
+#else
+not one
+#endif
+#if ZERO
+zero
+#elif ONE + ONE == 2
+two
+#elif ONE
+not taken, though the condition is true
+#else
+none
+#endif
+#if UNDEFINED_MACRO
+undefined
+#endif
+"#,
+        );
+
+        test(
+            r#"
+#define LEVEL 2
+#if LEVEL > 1
+#  if LEVEL > 2
+high
+#  else
+medium
+#    define RESULT medium_level
+#  endif
+#else
+#  if 1
+low, even though the inner condition is true
+#  else
+#  endif
+#  define RESULT low_level
+#endif
+RESULT
+#if 0
+#  if 1 / 0
+#  elif
+#  endif
+#elif (LEVEL == 2) && (0 || 1 / 1)
+ok
+#endif
+#if 0 && 1 / 0
+#elif 1 || 1 / 0
+short circuit
+#endif
+"#,
+        );
+    }
+
+    #[test]
+    fn conditional_defined() {
+        test(
+            r#"
+#define FEATURE
+#define ZERO 0
+#ifdef FEATURE
+feature
+#endif
+#ifndef FEATURE
+no feature
+#endif
+#ifdef ZERO
+zero is defined, even though its value is 0
+#endif
+#ifndef MISSING
+missing
+#endif
+#if defined FEATURE && defined(ZERO) && !defined ( MISSING )
+all defined
+#endif
+#undef FEATURE
+#ifdef FEATURE
+#elif defined FEATURE || defined(ZERO)
+undefined feature
+#endif
+#if 0
+#  ifdef ZERO
+#  endif
+#else
+else
+#endif
+#define definedX 1
+#if definedX
+definedX
+#endif
+#ifdef ZERO FEATURE
+extra tokens
+#endif
+#undef ZERO ZERO
+#ifndef ZERO
+zero is undefined
+#endif
+#define B 0
+#define HAS_B defined(B)
+#define HAS_C defined C
+#define HAS(x) defined(x)
+#if HAS_B && !HAS_C && HAS(HAS)
+defined from macros
+#endif
+"#,
+        );
+        let result = preprocess(
+            "#define A\n#ifdef A B\n#endif\n#undef A B\n#define HAS(x) defined(x)\n#if HAS(A)\n#endif\n#if HAS(1)\n#endif\n",
+        );
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "2:1: warning: extra tokens at end of #ifdef directive",
+                "4:1: warning: extra tokens at end of #undef directive",
+                "6:1: warning: this use of \"defined\" may not be portable",
+                "8:1: error: operator \"defined\" requires an identifier",
+                "8:1: error: missing binary operator in expression",
+            ]
+        );
+        let result = preprocess("#if 0\n#else\n#elif 1\n#else\n#endif\n");
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "3:1: error: #elif after #else",
+                "1:1: note: the conditional began here",
+                "4:1: error: #else after #else",
+                "1:1: note: the conditional began here",
+            ]
+        );
+    }
+
+    #[test]
+    fn conditional_expression() {
+        test(
+            r#"
+#define N 3
+#define NEG -1
+#if (N * 2 + 1) << 1 == 14 && N % 2 == 1 && (N ^ 1) == 2 && (N | 4) == 7 && (N & ~1) == 2
+arithmetic
+#endif
+#if NEG < 0u
+negative is not less than unsigned zero
+#elif NEG > 0u && -1 / 2 == 0 && -7 % 3 == -1 && NEG >> 1 == -1
+usual arithmetic conversions
+#endif
+#if (1 ? -1 : 0u) > 0 && (0 ? 1u : -1) > 0
+conditional operands are converted
+#endif
+#if 0xffffffffffffffff == -1 && 18446744073709551615u == -1 && 0x7fffffffffffffff > 0
+large constants
+#endif
+#if 010 == 8 && 0x10 == 16 && 10ULL == 10 && 1l == 1
+radix and suffixes
+#endif
+#if 'a' == 97 && '\0' == 0 && '\n' == 10 && '\x41' == 'A' && '\377' < 0 && 'ab' == 24930
+character constants
+#endif
+#if 'é' > 0 && 'é' == 0xc3a9 && '\u00e9' == 'é' && 'a\377' == 25087 && 'abcde' == 'bcde'
+plain character constants are encoded in UTF-8
+#endif
+#if L'\xffffffff' < 0 && L'é' == 0xe9 && L'ab' == 'b' && U'\U0001F600' == 0x1f600
+wide character constants
+#endif
+#if u'a' - 98 > 0 && U'a' - 98 > 0 && u'\U0001F600' == 0xde00 && u'\xfffff' == 0xffff
+char16_t and char32_t are unsigned
+#endif
+#if undefined_identifier || UNDEFINED_MACRO
+#else
+identifiers are zero
+#endif
+#if 0 && 1 / 0 || 1 ? 1 : 1 / 0
+short circuit
+#endif
+#if (2 || 0) == 1 && (0 && 2) == 0 && !0 == 1 && !5 == 0
+logical operators produce 0 or 1
+#endif
+#if 1 << 63 < 0 && 1u << 63 > 0 && -1 >> 63 == -1
+shifts keep the type of the left operand
+#endif
+"#,
+        );
+        let result = preprocess("#define F(x) x\n#if F || G\n#endif\n");
+        let steps: Vec<String> = result.trace[0]
+            .children
+            .iter()
+            .map(|node| node.event.to_string())
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "F is a function-like macro used without arguments, so it is replaced by 0 in the #if expression",
+                "G is not a macro, so it is replaced by 0 in the #if expression",
+                "Evaluating `0 || 0`. The result is `0`",
+            ]
+        );
+        // The expression is read from the pp-tokens, where `u8'a'` is two tokens.
+        let result = preprocess(
+            "#if u8'a'\n#endif\n#if \"a\"\n#endif\n#if '\\u0041'\n#endif\n#if '\\u00e'\n#endif\n",
+        );
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "1:1: error: missing binary operator in expression",
+                "3:1: error: string literal in preprocessor expression",
+                "5:1: error: \\u0041 is not a valid universal character",
+                "7:1: error: incomplete universal character name \\u00e",
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        test(
+            r#"
+#define EMPTY
+#define f(x) x
+#define g(x) [ x ]
+#define CAT(x, y) x ## y
+#define OBJ (1 + 2)
+#define E 5
+    indented line
+-EMPTY- f(-)- +f(+)
+a f( b ) c g(  1  ) h f(1)f(2)
+x=f(1) x = f(1) x=EMPTY=y x EMPTY y
+f(a
+b) after
+  f  (  1  )  ,  f( 2 )
+CAT(+,+)  CAT(x , y)   CAT(, y)  CAT(x,)
+OBJ OBJ
+1e+5 .5 0x1p-3 1E-E 0xE+E
+"E f(1) // /* not a comment" 'E' u8"E" L'E' /* E */ E // E
+"#,
+        );
+    }
+
+    #[test]
+    fn include() {
+        test_with_files(
+            r#"
+#include "local.h"
+#include <user.h>
+#include <system.h>
+#include "user.h"
+#include "include/nested.h"
+#include "guarded.h"
+#include "guarded.h"
+#include "once.h"
+#include "once.h"
+#include <dir//slashes.h>
+#include "include/dir/extra.h" extra tokens
+LOCAL USER SYSTEM NESTED SIBLING GUARDED ONCE SLASHES EXTRA
+"#,
+            &[
+                ("local.h", "#define LOCAL local\nfrom local.h\n"),
+                ("include/user.h", "#define USER user\n"),
+                ("system/system.h", "#define SYSTEM system\n"),
+                (
+                    "include/nested.h",
+                    "#include \"sibling.h\"\n#define NESTED nested\n",
+                ),
+                ("include/sibling.h", "#define SIBLING sibling\n"),
+                (
+                    "guarded.h",
+                    "#ifndef GUARDED\n#define GUARDED guarded\nfrom guarded.h\n#endif\n",
+                ),
+                ("once.h", "#pragma once\n#define ONCE once\nfrom once.h\n"),
+                ("include/dir/slashes.h", "#define SLASHES slashes\n"),
+                ("include/dir/extra.h", "#define EXTRA extra\n"),
+            ],
+        );
+
+        let mut files = InMemoryFiles::new();
+        files.add_file("main.c", "#include \"extra.h\" extra\nEXTRA\n");
+        files.add_file("extra.h", "#define EXTRA extra\n");
+        let mut preprocessor = MacroPreprocessor::with_file_provider(files);
+        let output = preprocessor.process_file("main.c").unwrap();
+        assert_eq!(output.trim(), "extra");
+        let diagnostics: Vec<_> = preprocessor
+            .take_diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            ["main.c:1:1: warning: extra tokens at end of #include directive"]
+        );
+    }
+
+    #[test]
+    fn computed_include() {
+        test_with_files(
+            r#"
+#define STRINGIFY_(x) #x
+#define STRINGIFY(x) STRINGIFY_(x)
+#define PLATFORM myos
+#define CONFIG_HEADER "config.h"
+#define USER_HEADER <user.h>
+#define PLATFORM_HEADER STRINGIFY(PLATFORM/impl.h)
+#include CONFIG_HEADER
+#include USER_HEADER
+#include STRINGIFY(PLATFORM/impl.h)
+#undef IMPL
+#include PLATFORM_HEADER
+CONFIG USER IMPL
+"#,
+            &[
+                ("config.h", "#define CONFIG config\n"),
+                ("include/user.h", "#define USER user\n"),
+                ("myos/impl.h", "#define IMPL impl\n"),
+            ],
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let result = preprocess(
+            r#"
+#define F(x) __VA_ARGS__ x
+#define D(a, a) a
+F(1)
+#define G(a, b) a b
+G(1) G(1, 2, 3)
+#endif
+#define H G(1)
+#define I H
+x I
+G(1,
+"#,
+        );
+        assert_eq!(standardize(result.output), "__VA_ARGS__ 1\nG G\nx G\nG");
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "2:14: warning: __VA_ARGS__ can only appear in the expansion of a C99 variadic macro",
+                "3:14: error: duplicate macro parameter \"a\"",
+                "6:1: error: macro \"G\" requires 2 arguments, but only 1 given",
+                "6:6: error: macro \"G\" passed 3 arguments, but takes just 2",
+                "7:1: error: #endif without #if",
+                "10:3: error: macro \"G\" requires 2 arguments, but only 1 given",
+                "8:11: note: macro \"G\" is invoked here, in the expansion of a macro",
+                "11:1: error: unterminated argument list invoking macro \"G\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn stringification() {
+        test(
+            r#"
+#define S(x) #x
+#define T(x) S(x)
+#define E
+#define P(x) x
+#define V(...) #__VA_ARGS__
+S("a, b" 'c' L"d\"" '\\' "\n")
+S(  a    +   b  ) S(a
+  b) S("a	b" a	b)
+S(é "é" 'é')
+T(+E+) T(-P(-)) T(a P() b) T(P(a)P(b))
+S(\\) S(a \ b) S() T(E)
+V(a , b,c) V( , ) V()
+"#,
+        );
+        let result = preprocess("#define S(x) #x\nS(a \\)\n");
+        assert_eq!(result.output, "\"a \"\n");
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            ["2:5: warning: invalid string literal, ignoring final '\\'"]
+        );
+    }
+
+    #[test]
+    fn literals() {
+        test(
+            r#"
+#define a A
+#define LOG(x) [x]
+#define F(x, y) x | y
+LOG("a, b)") LOG('\'') LOG(',') LOG(')')
+"a b" a 'a' "a /* b */ a" "//" a
+L"a, b" u8"a" u"a" U"a" L'a' u'a' U'a'
+F(L"x, y", u8"(, ") F('\'', '"') F("\"a, b)\"", "\\")
+#define BODY "a" a 'a' L"a"
+BODY
+#define u8 U8
+#define L LL
+u8"x" u8'x' u8 "x" L"x" L'x' L 'x'
+"#,
+        );
+        test("#define a A\ndon't a\na \"a, b\nL'a a\n");
+        let result = preprocess("#if 0\ndon't\n#endif\nL\"a\n");
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "2:4: warning: missing terminating ' character",
+                "4:1: warning: missing terminating \" character",
+            ]
+        );
+    }
+
+    #[test]
+    fn other_directives() {
+        test(
+            r#"
+#define N 10
+#line N "x.c"
+#line 5
+# 33 "f.c"
+#pragma omp parallel
+#ident "v1"
+#warning careful now
+#if 0
+#error skipped
+#foo
+#endif
+a
+"#,
+        );
+        let result = preprocess(
+            "#warning careful now\n#error stop here\n#foo bar\n#line x\n#line 4 5\n#line 4 \"a.c\" 1\n#line\nb\n",
+        );
+        assert_eq!(result.output, "b\n");
+        let diagnostics: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "1:1: warning: #warning careful now",
+                "2:1: error: #error stop here",
+                "3:1: error: invalid preprocessing directive #foo",
+                "4:1: error: \"x\" after #line is not a positive integer",
+                "5:1: error: \"5\" is not a valid filename",
+                "6:1: warning: extra tokens at end of #line directive",
+                "7:1: error: unexpected end of line after #line",
+            ]
+        );
+    }
+
+    #[test]
+    fn diagnostic_spans() {
+        let mut files = InMemoryFiles::new();
+        files.add_file("main.c", "#include \"macros.h\"\nF(1)\n");
+        files.add_file("macros.h", "#define F(x, y) x y\n#define G(x,)\n");
+        let mut preprocessor = MacroPreprocessor::with_file_provider(files);
+        preprocessor.process_file("main.c").unwrap();
+        let diagnostics: Vec<_> = preprocessor
+            .take_diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "macros.h:2:13: error: expected parameter name",
+                "main.c:2:1: error: macro \"F\" requires 2 arguments, but only 1 given",
+            ]
+        );
+    }
+
+    #[test]
+    fn trace() {
+        let result = preprocess("#define S(x) #x\n#define O O S(O)\nO\n");
+        assert_eq!(result.output.trim(), "O \"O\"");
+        let site = |name: &str, invocation: (usize, usize), definition: (usize, usize)| Site {
+            name: name.to_string(),
+            invocation: Span {
+                file: None,
+                line: invocation.0,
+                column: invocation.1,
+            },
+            definition: Span {
+                file: None,
+                line: definition.0,
+                column: definition.1,
+            },
+        };
+        let node = |event: Event, children: Vec<Node>| Node { event, children };
+        let s = site("S", (2, 13), (1, 9));
+        assert_eq!(
+            result.trace,
+            [node(
+                Event::ObjectExpansion {
+                    site: site("O", (3, 1), (2, 9)),
+                    replacement: "O S(O)".to_string(),
+                },
+                vec![node(
+                    Event::Rescan {
+                        site: site("O", (3, 1), (2, 9)),
+                        tokens: "O S(O)".to_string(),
+                        result: "O \"O\"".to_string(),
+                    },
+                    vec![
+                        node(
+                            Event::SelfReference {
+                                site: site("O", (2, 11), (2, 9)),
+                                hide_set: vec!["O".to_string()],
+                            },
+                            vec![],
+                        ),
+                        node(
+                            Event::FunctionExpansion {
+                                site: s.clone(),
+                                bindings: vec![Binding {
+                                    param: "x".to_string(),
+                                    arg: "O".to_string(),
+                                }],
+                                result: "\"O\"".to_string(),
+                            },
+                            vec![
+                                node(
+                                    Event::PrescanSkipped {
+                                        site: s.clone(),
+                                        param: "x".to_string(),
+                                        arg: "O".to_string(),
+                                    },
+                                    vec![],
+                                ),
+                                node(
+                                    Event::Stringification {
+                                        site: s.clone(),
+                                        operand: "O".to_string(),
+                                        result: "\"O\"".to_string(),
+                                    },
+                                    vec![],
+                                ),
+                            ],
+                        ),
+                    ],
+                )],
+            )]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let result = preprocess("#define F(x, ...) x\nF(1)\n");
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["output"].as_str().unwrap().trim(), "1");
+        assert_eq!(
+            json["macros"],
+            serde_json::json!([{
+                "name": "F",
+                "params": ["x", "__VA_ARGS__"],
+                "is_variadic": true,
+                "body": "x",
+                "span": { "file": null, "line": 1, "column": 9 },
+            }])
+        );
+        assert_eq!(json["diagnostics"], serde_json::json!([]));
+        let trace = &json["trace"][0];
+        assert_eq!(trace["kind"], "function_expansion");
+        assert_eq!(trace["site"]["invocation"]["line"], 2);
+        assert_eq!(
+            trace["bindings"],
+            serde_json::json!([
+                { "param": "x", "arg": "1" },
+                { "param": "__VA_ARGS__", "arg": "" },
+            ])
+        );
+    }
+
+    #[test]
+    fn graph() {
+        let result = preprocess("#define S(x) #x\n#define O O S(O)\nO\n");
+        let invocations = crate::invocations(&result.trace);
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(
+            crate::to_mermaid(invocations[0]),
+            r##"flowchart TD
+    n0["O<br/>=#gt; O S(O)"]
+    n1(["O is painted blue"])
+    n2["S<br/>x=#gt;`O`<br/>=#gt; #quot;O#quot;"]
+    n3(["x is not pre-expanded<br/>`O`"])
+    n4(["#35; `O`<br/>=#gt; #quot;O#quot;"])
+    n0 -->|rescan| n1
+    n0 -->|rescan| n2
+    n2 -->|substitution| n3
+    n2 -->|substitution| n4
+"##
+        );
+        assert!(crate::to_dot(invocations[0]).contains(r#"n0 -> n2 [label="rescan"];"#));
+    }
+
+    #[test]
+    fn html() {
+        let source = "#define S(x) #x\n#define O O S(O)\nint x = O + S(<O>);\n";
+        let html = crate::to_html(source, None, &preprocess(source));
+        assert!(html.contains(concat!(
+            "int x = <a class=\"invocation\" href=\"#invocation-0\">O</a> + ",
+            "<a class=\"invocation\" href=\"#invocation-1\">S</a>(&lt;O&gt;);\n"
+        )));
+        assert!(html.contains("Final result: <code class=\"result\">O &quot;O&quot;</code>"));
+        assert!(html.contains("Final result: <code class=\"result\">&quot;&lt;O&gt;&quot;</code>"));
+
+        let source = "#define A() a\n#define F(x) x A()\nF(A())\n";
+        let html = crate::to_html(source, None, &preprocess(source));
+        assert!(html.contains(concat!(
+            "<a class=\"invocation\" href=\"#invocation-0\">F</a>(",
+            "<a class=\"invocation\" href=\"#invocation-1\">A</a>())\n"
+        )));
+        assert!(!html.contains("invocation-2"));
+    }
+
+    #[test]
+    fn command_line_macros() {
+        let mut preprocessor = MacroPreprocessor::new();
+        preprocessor.define("A");
+        preprocessor.define("B=2");
+        preprocessor.define("F(x)=x * B");
+        preprocessor.define("C");
+        preprocessor.undefine("C");
+        assert_eq!(preprocessor.process_mut("A F(3) C\n"), "1 3 * 2 C\n");
+        let definitions = preprocessor.definitions();
+        assert_eq!(definitions[0].span.to_string(), "<command-line>:1:9");
+    }
+
+    #[test]
+    fn check() {
+        let result = preprocess("#define F(x) x + x\nint a;\n\nint b = F(1);\nint c;\n");
+        let reference = "int a;\nint b = 1 + 1 ;\n\nint c;\nint d;\n";
+        let mismatches = crate::compare(&result, reference);
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].span.as_ref().unwrap().to_string(), "4:1");
+        assert_eq!(mismatches[0].actual, ["int b = 1 + 1;"]);
+        assert_eq!(mismatches[0].expected, ["int b = 1 + 1 ;"]);
+        assert_eq!(mismatches[0].trace, result.trace);
+        assert_eq!(mismatches[1].span, None);
+        assert!(mismatches[1].actual.is_empty());
+        assert_eq!(mismatches[1].expected, ["int d;"]);
+
+        let source = "#if 0\n#elif 1\n#ifdef X\n#else\n#ifdef Y\n#endif\nx\n#endif\n#endif\ny\n";
+        let result = preprocess(source);
+        let mismatches = crate::compare(&result, "z\ny\n");
+        assert_eq!(mismatches.len(), 1);
+        let lines: Vec<_> = mismatches[0]
+            .trace
+            .iter()
+            .map(|node| node.event.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "The condition `0` of #if at 1:1 evaluates to 0, so the group is skipped",
+                "The condition `1` of #elif at 2:1 evaluates to 1, so the group is kept",
+                "X is not defined, so the #ifdef group at 3:1 is skipped",
+                "The #else group at 4:1 is kept because no earlier group of the conditional \
+                 starting at 3:1 was taken",
+            ]
+        );
+    }
+
+    /// Compares random programs with the compiler. A failing program can be generated alone
+    /// with `GENERATED_VERSION=V GENERATED_SEED=N cargo test generated`.
+    #[test]
+    fn generated() {
+        fn env<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+        let versions = match env("GENERATED_VERSION") {
+            Some(version) => version..=version,
+            None => crate::generate::VERSIONS,
+        };
+        let seeds = match env("GENERATED_SEED") {
+            Some(seed) => seed..seed + 1,
+            None => 0..200,
+        };
+        for version in versions {
+            for seed in seeds.clone() {
+                let source_code = crate::generate::generate(version, seed);
+                let expanded_code = standardize(preprocess(&source_code).output);
+                let reference = get_reference_result(&source_code).unwrap_or_else(|| {
+                    panic!(
+                        "version {} seed {}: cc rejects\n{}",
+                        version, seed, source_code
+                    )
+                });
+                let reference = standardize(reference);
+                assert_eq!(
+                    expanded_code, reference,
+                    "version {} seed {}: Source Code:\n{}\n\nExpanded Code:\n{}\n\nReference:\n{}",
+                    version, seed, source_code, expanded_code, reference,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn without_recording() {
+        for seed in 0..100 {
+            let source_code = crate::generate::generate(2, seed) + "M0 M1 error(\n#if M0\n#endif\n";
+            let recorded = preprocess(&source_code);
+            let mut preprocessor = MacroPreprocessor::new();
+            preprocessor.set_recording(false);
+            let result = preprocessor.preprocess(&source_code);
+            assert_eq!(result.output, recorded.output, "seed {}", seed);
+            assert_eq!(result.diagnostics, recorded.diagnostics, "seed {}", seed);
+            assert!(result.trace.is_empty() && result.rewrites.is_empty());
+        }
+    }
+
+    #[test]
+    fn minimize() {
+        let source = "#define A 1\n#define B 2\n#define C 3\n#define ADD(x, y) x + y\nint a = C;\nint b = ADD(A, ADD(B, C));\nint c = A;\n";
+        let is_interesting = |source: &str| preprocess(source).output.contains("2 + 3");
+        assert_eq!(
+            crate::minimize(source, is_interesting),
+            "#define B 2\n#define C 3\n#define ADD(x, y) x + y\n    ADD(B, C)\n"
+        );
+    }
+
+    #[test]
+    fn definition_lines() {
+        let result = preprocess("#define O\n#define F(a, b) a ## b\n#define G(x, args...) x args\n#define V(...) __VA_ARGS__\n");
+        let lines = result.macros.iter().map(ToString::to_string).collect_vec();
+        assert_eq!(
+            lines,
+            [
+                "#define F(a, b) a ## b",
+                "#define G(x, args...) x args",
+                "#define O",
+                "#define V(...) __VA_ARGS__",
+            ]
+        );
+    }
+
+    #[test]
+    fn continuation() {
+        let mut preprocessor = MacroPreprocessor::new();
+        preprocessor.process_continuation("#define A 1\n", "<repl>", 1);
+        let output = preprocessor.process_continuation("#define F(x) x\nF(A)\n", "<repl>", 2);
+        assert_eq!(output.trim(), "1");
+        let spans: Vec<String> = preprocessor
+            .definitions()
+            .iter()
+            .map(|definition| definition.span.to_string())
+            .collect();
+        assert_eq!(spans, ["<repl>:1:9", "<repl>:2:9"]);
+        assert_eq!(
+            preprocessor.take_trace()[0]
+                .event
+                .site()
+                .unwrap()
+                .invocation
+                .line,
+            3
+        );
+    }
+
+    #[test]
+    fn debugger() {
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+        impl Debugger for Recorder {
+            fn before_replacement(&mut self, replacement: &Replacement) {
+                self.0.borrow_mut().push(format!(
+                    "{} {} {{{}}} {}",
+                    replacement.depth,
+                    replacement.site.name,
+                    replacement.replacement_hide_set.join(", "),
+                    replacement.bindings.iter().join(", ")
+                ));
+            }
+        }
+        let replacements = Rc::new(RefCell::new(Vec::new()));
+        let mut preprocessor = MacroPreprocessor::new();
+        preprocessor.set_debugger(Recorder(replacements.clone()));
+        preprocessor.process_mut("#define O F(1)\n#define F(x) x\n#define G(x) F(x)\nG(O) O\n");
+        assert_eq!(
+            *replacements.borrow(),
+            [
+                "3 O {O} ",
+                "5 F {F, O} x=>`1`",
+                "1 G {G} x=>`1`",
+                "3 F {F, G} x=>`1`",
+                "1 O {O} ",
+                "3 F {F, O} x=>`1`",
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites() {
+        let mut preprocessor = MacroPreprocessor::new();
+        preprocessor.set_recording_rewrites(true);
+        let result = preprocessor.preprocess(
+            r#"#define EMPTY()
+#define DEFER(id) id EMPTY()
+#define EXPAND(...) __VA_ARGS__
+#define A() 123
+int x = DEFER(A)();
+EXPAND(DEFER(A)())
+"#,
+        );
+        assert_eq!(
+            crate::rewrite_view(&result.rewrites),
+            r#"5:9:
+   int x = DEFER(A)();
+          |^^^^^^^^
+=> int x = A EMPTY()();
+            |^^^^^^^
+=> int x = A ();
+            |
+
+6:8:
+   EXPAND(DEFER(A)())
+         |^^^^^^^^
+=> EXPAND(A EMPTY()())
+           |^^^^^^^
+=> EXPAND(A ())
+  |^^^^^^^^^^^^
+=> A ()
+  |^^^^
+=> 123
+  |
+"#
+        );
+        // A line is stored once for each chain of rewrites.
+        let lines = result
+            .rewrites
+            .iter()
+            .filter(|rewrite| rewrite.line.is_some());
+        assert_eq!(lines.count(), 2);
+    }
 }
@@ -0,0 +1,164 @@
+use std::io::{BufRead, Write};
+
+use c_macro_explainer::MacroPreprocessor;
+
+use crate::format_trace;
+
+const HELP: &str = "\
+Type `#define` lines and code to see them explained; end a line with `\\` to continue it.
+Commands:
+  :list         list the macros defined so far
+  :show NAME    show the definition of a macro
+  :undef NAME   undefine a macro
+  :reset        forget the macros defined here
+  :load FILE    preprocess a file, keeping its macros
+  :save FILE    write the macros defined so far to a file as `#define` lines
+  :help         show this message
+  :quit         leave";
+
+/// The file name of the inputs in the locations shown.
+const FILE: &str = "<repl>";
+
+/// Reads code and commands from the standard input until EOF or `:quit`, explaining each
+/// input with the macros defined by the previous ones. `new` creates the preprocessor,
+/// again on `:reset`.
+pub fn run(new: impl Fn() -> MacroPreprocessor) {
+    let mut preprocessor = new();
+    let mut lines = std::io::stdin().lock().lines();
+    // The inputs are numbered as the lines of one file, so that their locations differ.
+    let mut line = 1;
+    println!("Type :help for the commands.");
+    while let Some(mut input) = read_line(&mut lines, "> ") {
+        // A conditional group is read whole, since each input is preprocessed separately.
+        if !input.trim_start().starts_with(':') {
+            while conditional_depth(&input) > 0 {
+                let Some(next) = read_line(&mut lines, ". ") else {
+                    break;
+                };
+                input += "\n";
+                input += &next;
+            }
+        }
+        let first_line = line;
+        line += input.matches('\n').count() + 1;
+        let (command, argument) = match input.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (input.trim(), ""),
+        };
+        match command {
+            "" => {}
+            ":quit" | ":q" => break,
+            ":help" => println!("{}", HELP),
+            ":list" => {
+                for definition in preprocessor.definitions() {
+                    println!("{}", definition);
+                }
+            }
+            ":show" => match find(&preprocessor, argument) {
+                Some(definition) => println!("{}: {}", definition.span, definition),
+                None => eprintln!("error: {} is not defined", argument),
+            },
+            ":undef" => match find(&preprocessor, argument) {
+                Some(_) => explain(
+                    &mut preprocessor,
+                    &format!("#undef {}", argument),
+                    first_line,
+                ),
+                None => eprintln!("error: {} is not defined", argument),
+            },
+            ":reset" => {
+                preprocessor = new();
+                println!("Every macro defined here is forgotten.");
+            }
+            ":load" => match preprocessor.process_file(argument) {
+                Some(output) => {
+                    report(&mut preprocessor, &output);
+                    println!("Loaded {}.", argument);
+                }
+                None => eprintln!("error: cannot read {}", argument),
+            },
+            ":save" => {
+                let definitions = preprocessor
+                    .definitions()
+                    .iter()
+                    .map(|definition| format!("{}\n", definition))
+                    .collect::<String>();
+                match std::fs::write(argument, definitions) {
+                    Ok(()) => println!("Saved the macros to {}.", argument),
+                    Err(err) => eprintln!("error: cannot write {}: {}", argument, err),
+                }
+            }
+            command if command.starts_with(':') => {
+                eprintln!("error: unknown command `{}`; type :help", command)
+            }
+            _ => explain(&mut preprocessor, &input, first_line),
+        }
+    }
+}
+
+fn find(
+    preprocessor: &MacroPreprocessor,
+    name: &str,
+) -> Option<c_macro_explainer::MacroDefinition> {
+    preprocessor
+        .definitions()
+        .into_iter()
+        .find(|definition| definition.name == name)
+}
+
+/// Reads a line after printing `prompt`. A backslash-newline continues it on the next line,
+/// as in a source file.
+fn read_line(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    prompt: &str,
+) -> Option<String> {
+    print!("{}", prompt);
+    std::io::stdout().flush().unwrap();
+    let mut input = lines.next()?.ok()?;
+    while input.ends_with('\\') {
+        print!(". ");
+        std::io::stdout().flush().unwrap();
+        let Some(line) = lines.next().and_then(Result::ok) else {
+            break;
+        };
+        input += "\n";
+        input += &line;
+    }
+    Some(input)
+}
+
+/// The number of conditional groups that `input` opens and leaves unterminated.
+fn conditional_depth(input: &str) -> isize {
+    input
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix('#'))
+        .map(|directive| {
+            let name = directive.trim_start();
+            let name = &name[..name
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(name.len())];
+            match name {
+                "if" | "ifdef" | "ifndef" => 1,
+                "endif" => -1,
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+/// Preprocesses one input starting at `line` and prints its explanation and output.
+fn explain(preprocessor: &mut MacroPreprocessor, input: &str, line: usize) {
+    let output = preprocessor.process_continuation(input, FILE, line);
+    report(preprocessor, &output);
+}
+
+fn report(preprocessor: &mut MacroPreprocessor, output: &str) {
+    print!("{}", format_trace(&preprocessor.take_trace(), 0));
+    preprocessor.take_rewrites();
+    for diagnostic in preprocessor.take_diagnostics() {
+        eprintln!("{}", diagnostic);
+    }
+    if !output.trim().is_empty() {
+        println!("{}", output.trim_end());
+    }
+}
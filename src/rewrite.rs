@@ -0,0 +1,92 @@
+use serde::Serialize;
+use std::ops::Range;
+
+use crate::diagnostic::Span;
+
+/// A macro invocation replaced in the line being scanned. Positions are byte offsets in
+/// the line before the replacement, whose tokens are spelled as in explanations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rewrite {
+    /// Where the replaced invocation is.
+    pub span: Span,
+    /// The whole line before the replacement, or `None` if it is the line left by the
+    /// previous rewrite, so that a line rewritten many times is stored once. Inside the
+    /// pre-expansion of an argument, the invocation taking the argument is shown around it.
+    pub line: Option<String>,
+    /// The length of the prefix of the line already scanned for macros, which are not
+    /// expanded again.
+    pub scanned: usize,
+    /// The invocation being replaced, with its arguments pre-expanded.
+    pub redex: Range<usize>,
+    /// The part of the line that changes. It covers the redex, except for the whitespace
+    /// around it that the replacement keeps.
+    pub replaced: Range<usize>,
+    /// The text taking the place of `replaced`.
+    pub replacement: String,
+}
+
+impl Rewrite {
+    /// Returns the line after the replacement, given `line` before it.
+    pub fn apply(&self, line: &str) -> String {
+        let (before, after) = (&line[..self.replaced.start], &line[self.replaced.end..]);
+        format!("{}{}{}", before, self.replacement, after)
+    }
+}
+
+/// Renders rewrites as chains of lines, each rewritten from the previous one, like
+///
+/// ```text
+///    DEFER(A)()
+///   |^^^^^^^^
+/// => A EMPTY()()
+///     |^^^^^^^
+/// => A ()
+///     |
+/// ```
+///
+/// where `|` marks the end of the scanned prefix and `^` the invocation being replaced.
+/// Each chain starts with the location of its first invocation.
+pub fn rewrite_view(rewrites: &[Rewrite]) -> String {
+    let mut view = String::new();
+    let mut line = String::new();
+    for (i, rewrite) in rewrites.iter().enumerate() {
+        match &rewrite.line {
+            Some(start) => {
+                if i > 0 {
+                    view += "\n";
+                }
+                line = start.clone();
+                view += &format!("{}:\n   {}\n", rewrite.span, line);
+            }
+            None => view += &format!("=> {}\n", line),
+        }
+        let mut markers = boundary(&line, rewrite.scanned);
+        let redex_column = 3 + width(&line[..rewrite.redex.start]);
+        markers += &" ".repeat(redex_column.saturating_sub(width(&markers)));
+        markers += &"^".repeat(width(&line[rewrite.redex.clone()]));
+        view += &format!("{}\n", markers);
+        line = rewrite.apply(&line);
+        let is_chain_end = rewrites.get(i + 1).is_none_or(|next| next.line.is_some());
+        if is_chain_end {
+            view += &format!("=> {}\n{}\n", line, boundary(&line, rewrite.scanned));
+        }
+    }
+    view
+}
+
+/// A marker line with `|` between the first `scanned` bytes of a line printed after a
+/// three-column prefix and the rest, under the separating space if there is one.
+fn boundary(line: &str, scanned: usize) -> String {
+    let column = if scanned == 0 {
+        2
+    } else if line[scanned..].starts_with(' ') {
+        3 + width(&line[..scanned])
+    } else {
+        2 + width(&line[..scanned])
+    };
+    format!("{}|", " ".repeat(column))
+}
+
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
@@ -0,0 +1,484 @@
+use serde::Serialize;
+use std::{fmt, mem, path::PathBuf};
+
+use crate::diagnostic::Span;
+
+/// Where a macro is invoked and where it is defined.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Site {
+    pub name: String,
+    /// The macro name of the invocation. For an invocation produced by the expansion of
+    /// another macro, it is in the body of the other macro.
+    pub invocation: Span,
+    /// The macro name in the `#define`.
+    pub definition: Span,
+}
+
+/// The argument given to a parameter of a function-like macro.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Binding {
+    pub param: String,
+    /// The argument as substituted: fully macro-expanded, unless the parameter is only an
+    /// operand of `#` or `##`.
+    pub arg: String,
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}=>`{}`", self.param, self.arg)
+    }
+}
+
+/// A step of preprocessing, together with the steps it is made of, in the order they
+/// happen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub event: Event,
+    pub children: Vec<Node>,
+}
+
+/// A step of preprocessing. Tokens are given as their spelling.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// An object-like macro is replaced by its body. The only child is the rescan, if it
+    /// expands anything.
+    ObjectExpansion { site: Site, replacement: String },
+    /// A function-like macro invocation is replaced by its body, after substitution and
+    /// pasting. The children are the argument prescans, the steps of substitution, and the
+    /// rescan.
+    FunctionExpansion {
+        site: Site,
+        bindings: Vec<Binding>,
+        result: String,
+    },
+    /// An argument is fully macro-expanded before substitution. The children are the
+    /// expansions inside the argument.
+    Prescan {
+        site: Site,
+        param: String,
+        arg: String,
+        expanded: String,
+    },
+    /// The expansion of a macro is scanned again, together with the rest of the source, for
+    /// more macros to expand. The children are the invocations starting in the expansion.
+    Rescan {
+        site: Site,
+        tokens: String,
+        /// The tokens that replace the invocation once no more macros can be expanded,
+        /// including those taken from after it by the invocations rescanned.
+        result: String,
+    },
+    /// An argument is not macro-expanded before substitution, because the parameter is an
+    /// operand of `#` or `##`.
+    PrescanSkipped {
+        site: Site,
+        param: String,
+        arg: String,
+    },
+    /// A macro name is not expanded because it was produced by the expansion of the macro
+    /// itself.
+    SelfReference { site: Site, hide_set: Vec<String> },
+    /// The `#` operator turns an operand into a string literal.
+    Stringification {
+        site: Site,
+        operand: String,
+        result: String,
+    },
+    /// The `##` operator concatenates two tokens.
+    Pasting {
+        site: Site,
+        lhs: String,
+        rhs: String,
+        result: String,
+    },
+    /// `__VA_OPT__ ( ... )` is replaced by its content if the variable arguments are present,
+    /// and removed otherwise.
+    VaOpt {
+        site: Site,
+        va_opt: String,
+        /// The variable arguments before macro expansion.
+        variable_arguments: String,
+        replacement: Option<String>,
+    },
+    /// The comma before `## args` is removed if the variable arguments are omitted, as in
+    /// GNU C.
+    CommaElision {
+        site: Site,
+        param: String,
+        /// The variable arguments if they are given, even if empty.
+        variable_arguments: Option<String>,
+    },
+    /// A `defined` operator in the condition of `#if` or `#elif`.
+    Defined {
+        span: Span,
+        operator: String,
+        name: String,
+        definition: Option<Span>,
+    },
+    /// The condition of `#if` or `#elif` after replacing `defined` and expanding macros,
+    /// if it differs from the written one.
+    ConditionExpanded { span: Span, condition: String },
+    /// A step of evaluating the condition of `#if` or `#elif`, such as applying an operator.
+    Evaluation { span: Span, step: String },
+    /// The condition of `#if` or `#elif` is evaluated. The children are the steps of the
+    /// evaluation.
+    Condition {
+        span: Span,
+        directive: String,
+        condition: String,
+        value: String,
+        is_kept: bool,
+    },
+    /// `#ifdef` or `#ifndef` checks whether a macro is defined.
+    Ifdef {
+        span: Span,
+        directive: String,
+        name: String,
+        definition: Option<Span>,
+        is_kept: bool,
+    },
+    /// `#elif` or `#else` is skipped because an earlier group of the conditional starting at
+    /// `conditional` was kept.
+    GroupSkipped {
+        span: Span,
+        directive: String,
+        conditional: Span,
+    },
+    /// `#else` is kept because no earlier group of the conditional starting at `conditional`
+    /// was kept.
+    ElseKept { span: Span, conditional: Span },
+    /// The operand of `#include` is macro-expanded because it is not a header name. The
+    /// children are the expansions.
+    HeaderNameExpansion {
+        span: Span,
+        operand: String,
+        header_name: String,
+    },
+    /// `#include` finds a file. It is skipped if it contains `#pragma once` and has already
+    /// been included. The children are the steps of preprocessing the file.
+    Include {
+        span: Span,
+        header_name: String,
+        path: PathBuf,
+        is_skipped: bool,
+    },
+}
+
+/// Explains whether a macro is defined, and where.
+struct IsDefined<'a>(&'a str, Option<&'a Span>);
+
+impl fmt::Display for IsDefined<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Some(definition) => write!(f, "{} is defined (at {})", self.0, definition),
+            None => write!(f, "{} is not defined", self.0),
+        }
+    }
+}
+
+fn kept_or_skipped(is_kept: bool) -> &'static str {
+    if is_kept {
+        "kept"
+    } else {
+        "skipped"
+    }
+}
+
+impl Event {
+    /// The macro invocation the event is about, if any.
+    pub fn site(&self) -> Option<&Site> {
+        match self {
+            Event::ObjectExpansion { site, .. }
+            | Event::FunctionExpansion { site, .. }
+            | Event::Prescan { site, .. }
+            | Event::Rescan { site, .. }
+            | Event::PrescanSkipped { site, .. }
+            | Event::SelfReference { site, .. }
+            | Event::Stringification { site, .. }
+            | Event::Pasting { site, .. }
+            | Event::VaOpt { site, .. }
+            | Event::CommaElision { site, .. } => Some(site),
+            _ => None,
+        }
+    }
+
+    /// Writes the explanation of the event, without where it happens.
+    fn explain(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::ObjectExpansion { site, replacement } => write!(
+                f,
+                "Expanding object-like macro {} to `{}`",
+                site.name, replacement
+            ),
+            Event::FunctionExpansion {
+                site,
+                bindings,
+                result,
+            } => {
+                write!(f, "Expanding function-like macro {} with args {{", site.name)?;
+                for (i, binding) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", binding)?;
+                }
+                write!(f, "}}. The result is `{}`", result)
+            }
+            Event::Prescan {
+                site,
+                param,
+                arg,
+                expanded,
+            } => write!(
+                f,
+                "Pre-expanding the argument `{}` of {} for {} gives `{}`",
+                arg, site.name, param, expanded
+            ),
+            Event::Rescan { site, tokens, .. } => write!(
+                f,
+                "Rescanning `{}` produced by {} for more macros",
+                tokens, site.name
+            ),
+            Event::PrescanSkipped { site, param, arg } => write!(
+                f,
+                "{} is an operand of # or ## in the body of {}, so the pre-expansion of `{}` is skipped there",
+                param, site.name, arg
+            ),
+            Event::SelfReference { site, hide_set } => write!(
+                f,
+                "{} is painted blue and will never be expanded, because it was produced by the expansion of {} itself (its hide set is {{{}}})",
+                site.name,
+                site.name,
+                hide_set.join(", ")
+            ),
+            Event::Stringification {
+                operand, result, ..
+            } => write!(f, "Stringifying `{}` gives {}", operand, result),
+            Event::Pasting {
+                lhs, rhs, result, ..
+            } => write!(f, "Pasting `{}` and `{}` gives `{}`", lhs, rhs, result),
+            Event::VaOpt {
+                site,
+                va_opt,
+                variable_arguments,
+                replacement,
+            } => match replacement {
+                Some(replacement) => write!(
+                    f,
+                    "The variable arguments of {} are present, so `{}` is replaced by `{}`",
+                    site.name, va_opt, replacement
+                ),
+                None if variable_arguments.is_empty() => write!(
+                    f,
+                    "No variable arguments are given to {}, so `{}` is removed",
+                    site.name, va_opt
+                ),
+                None => write!(
+                    f,
+                    "The variable arguments `{}` of {} expand to nothing, so `{}` is removed",
+                    variable_arguments, site.name, va_opt
+                ),
+            },
+            Event::CommaElision {
+                site,
+                param,
+                variable_arguments,
+            } => match variable_arguments {
+                None => write!(
+                    f,
+                    "The variable arguments of {} are omitted, so the comma before `## {}` is swallowed",
+                    site.name, param
+                ),
+                Some(args) => {
+                    let args = match args.is_empty() {
+                        true => "empty but given".to_string(),
+                        false => format!("`{}`", args),
+                    };
+                    write!(
+                        f,
+                        "The variable arguments of {} are {}, so the comma before `## {}` is kept and nothing is pasted",
+                        site.name, args, param
+                    )
+                }
+            },
+            Event::Defined {
+                operator,
+                name,
+                definition,
+                ..
+            } => {
+                write!(
+                    f,
+                    "`{}` is {} because {}",
+                    operator,
+                    definition.is_some() as i64,
+                    IsDefined(name, definition.as_ref())
+                )
+            }
+            Event::ConditionExpanded { condition, .. } => {
+                write!(
+                    f,
+                    "After replacing defined and expanding macros, the condition is `{}`",
+                    condition
+                )
+            }
+            Event::Evaluation { step, .. } => write!(f, "{}", step),
+            Event::Condition {
+                span,
+                directive,
+                condition,
+                value,
+                is_kept,
+            } => {
+                write!(
+                    f,
+                    "The condition `{}` of #{} at {} evaluates to {}, so the group is {}",
+                    condition,
+                    directive,
+                    span,
+                    value,
+                    kept_or_skipped(*is_kept)
+                )
+            }
+            Event::Ifdef {
+                span,
+                directive,
+                name,
+                definition,
+                is_kept,
+            } => {
+                write!(
+                    f,
+                    "{}, so the #{} group at {} is {}",
+                    IsDefined(name, definition.as_ref()),
+                    directive,
+                    span,
+                    kept_or_skipped(*is_kept)
+                )
+            }
+            Event::GroupSkipped {
+                span,
+                directive,
+                conditional,
+            } => {
+                write!(
+                    f,
+                    "The #{} group at {} is skipped because an earlier group of the conditional starting at {} was taken",
+                    directive, span, conditional
+                )
+            }
+            Event::ElseKept { span, conditional } => {
+                write!(
+                    f,
+                    "The #else group at {} is kept because no earlier group of the conditional starting at {} was taken",
+                    span, conditional
+                )
+            }
+            Event::HeaderNameExpansion {
+                span,
+                operand,
+                header_name,
+            } => {
+                write!(
+                    f,
+                    "The operand `{}` of #include at {} is not a header name, so it is macro-expanded to `{}`",
+                    operand, span, header_name
+                )
+            }
+            Event::Include {
+                span,
+                header_name,
+                path,
+                is_skipped,
+            } => {
+                write!(
+                    f,
+                    "{} included at {} resolves to `{}`",
+                    header_name,
+                    span,
+                    path.display()
+                )?;
+                if *is_skipped {
+                    write!(f, ", which is skipped because it contains #pragma once")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Explains the event, citing the invocation site and the definition site of the macro it
+/// is about.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.site() {
+            Some(site) => {
+                write!(f, "{}: ", site.invocation)?;
+                self.explain(f)?;
+                write!(f, " ({} is defined at {})", site.name, site.definition)
+            }
+            None => self.explain(f),
+        }
+    }
+}
+
+/// Builds the trace tree from the steps recorded while preprocessing.
+#[derive(Debug)]
+pub struct TraceBuilder {
+    /// The children of the nodes being built, from the outermost. The first one holds the
+    /// finished top-level nodes.
+    open: Vec<Vec<Node>>,
+}
+
+impl Default for TraceBuilder {
+    fn default() -> Self {
+        Self {
+            open: vec![Vec::new()],
+        }
+    }
+}
+
+impl TraceBuilder {
+    /// Adds a step without children to the node being built.
+    pub fn record(&mut self, event: Event) {
+        self.push(Node {
+            event,
+            children: Vec::new(),
+        });
+    }
+
+    /// Starts a node. The steps recorded until it is closed become its children.
+    pub fn open(&mut self) {
+        self.open.push(Vec::new());
+    }
+
+    /// Finishes the node started last with its event.
+    pub fn close(&mut self, event: Event) {
+        let children = self.open.pop().unwrap();
+        self.push(Node { event, children });
+    }
+
+    /// Finishes the node started last, dropping it if it has no children.
+    pub fn close_unless_empty(&mut self, event: Event) {
+        let children = self.open.pop().unwrap();
+        if !children.is_empty() {
+            self.push(Node { event, children });
+        }
+    }
+
+    /// Returns the number of nodes being built.
+    pub fn depth(&self) -> usize {
+        self.open.len() - 1
+    }
+
+    /// Returns the finished top-level nodes.
+    pub fn take(&mut self) -> Vec<Node> {
+        mem::take(&mut self.open[0])
+    }
+
+    fn push(&mut self, node: Node) {
+        self.open.last_mut().unwrap().push(node);
+    }
+}